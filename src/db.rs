@@ -1,42 +1,480 @@
-use anyhow::Error;
+#[cfg(test)]
+mod bench;
+mod legacy;
+
+use crate::graph::{FeaturePolicy, Graph, Target};
+use anyhow::{anyhow, Context, Error};
 use chrono::serde::ts_seconds;
-use chrono::{DateTime, TimeZone, Utc};
-use crates_index::{Crate, Dependency, GitIndex};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use crates_index::{Crate, Dependency, DependencyKind, GitIndex, SparseIndex};
 use dlhn::{Deserializer, Serializer};
-use git2::{BranchType, Repository, ResetType};
-use semver::{Version, VersionReq};
+use git2::build::RepoBuilder;
+use git2::{BranchType, Repository, Sort};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use reqwest::StatusCode;
+use semver::Version;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tempfile::tempdir;
 
+/// db_v3 is kept for the released versions, and the current version is in its own directory
+pub const DB_URL: &str = "https://raw.githubusercontent.com/dalance/cargo-trend/master/db_v4";
+
+/// Version of db format, which is changed when `Entry` or the chunk layout is changed
+pub const DB_VERSION: u32 = 4;
+
+/// Chunks are split when they reach this number of entries, but a crate is never split in base chunks
+const CHUNK_ENTRIES: usize = 100000;
+
+/// Recent chunks are merged into base chunks when they exceed this number of entries
+const RECENT_ENTRIES: usize = 500000;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Db {
     pub update: DateTime<Utc>,
     pub map: HashMap<String, Vec<Entry>>,
+    /// Target triples of per-target dependents
+    pub targets: Vec<String>,
+    /// Features enabled on root crates while building db
+    pub features: FeaturePolicy,
+    /// Whether dev-dependencies of dependencies are counted as transitive dependents
+    pub dev_transitive: bool,
+    /// Whether dependencies are resolved to yanked versions too
+    pub include_yanked: bool,
+    /// Whether snapshots have the crates of a crate list only instead of the whole index
+    pub partial: bool,
+    /// Time of the first snapshot which has dependents of each crate
+    ///
+    /// Entries before it have no added or removed dependents like db of the older versions, and
+    /// all dependents are added at it.
+    pub edges_since: Option<DateTime<Utc>>,
+    /// Direct dependents of each crate at the last update, which is restored by `update`
+    #[serde(skip)]
+    current: HashMap<String, BTreeSet<String>>,
+    /// Entries until this time are in base chunks
+    #[serde(skip)]
+    base: DateTime<Utc>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DbHeader {
+    /// db_v3 has no version field
+    #[serde(default = "db_v3")]
+    pub version: u32,
     pub update: DateTime<Utc>,
     pub hash: Vec<String>,
+    #[serde(default)]
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub features: FeaturePolicy,
+    #[serde(default)]
+    pub dev_transitive: bool,
+    #[serde(default)]
+    pub include_yanked: bool,
+    #[serde(default)]
+    pub partial: bool,
+    #[serde(default)]
+    pub edges_since: Option<DateTime<Utc>>,
+    /// The first crate name of each base chunk
+    ///
+    /// Since db_v4, base chunks are followed by recent chunks. Base chunks have the history of
+    /// crates until `base` sorted by crate name, and recent chunks have the later entries sorted
+    /// by time, so an update appends entries to the last recent chunk only.
+    #[serde(default)]
+    pub index: Vec<String>,
+    #[serde(default)]
+    pub base: DateTime<Utc>,
+}
+
+fn db_v3() -> u32 {
+    3
+}
+
+impl DbHeader {
+    /// Chunks which contain the crates, or all chunks if `crates` is None
+    ///
+    /// Any crate may have entries in recent chunks, so all recent chunks are contained.
+    fn chunks(&self, crates: Option<&[String]>) -> Vec<usize> {
+        let Some(crates) = crates else {
+            return (0..self.hash.len()).collect();
+        };
+
+        let mut ret = BTreeSet::new();
+        for name in crates {
+            let i = self.index.partition_point(|x| x <= name);
+            if i > 0 {
+                ret.insert(i - 1);
+            }
+        }
+        ret.extend(self.index.len()..self.hash.len());
+        ret.into_iter().collect()
+    }
 }
 
+/// Base chunk, which has whole entries until `DbHeader::base` of each crate
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DbChunk {
+    pub crates: Vec<Columns>,
+}
+
+/// Recent chunk, which has entries after `DbHeader::base` sorted by time
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecentChunk {
     pub data: Vec<(String, Entry)>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Entries of a crate, which are stored by column
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Columns {
+    pub name: String,
+    pub time: Vec<i64>,
+    pub direct_dependents: Vec<u64>,
+    pub transitive_dependents: Vec<u64>,
+    pub total_crates: Vec<u64>,
+    pub normal_dependents: Vec<u64>,
+    pub dev_dependents: Vec<u64>,
+    pub build_dependents: Vec<u64>,
+    pub target_direct_dependents: Vec<Vec<u64>>,
+    pub target_transitive_dependents: Vec<Vec<u64>>,
+    pub yanked_versions: Vec<u64>,
+    pub total_versions: Vec<u64>,
+    pub compat_dependents: Vec<Vec<(String, u64)>>,
+    pub latest_dependents: Vec<u64>,
+    pub added_dependents: Vec<Vec<String>>,
+    pub removed_dependents: Vec<Vec<String>>,
+}
+
+impl Columns {
+    fn new(name: &str, entries: &[Entry]) -> Self {
+        fn column<T: Clone>(entries: &[Entry], f: impl Fn(&Entry) -> &T) -> Vec<T> {
+            entries.iter().map(|x| f(x).clone()).collect()
+        }
+
+        Columns {
+            name: String::from(name),
+            time: entries.iter().map(|x| x.time.timestamp()).collect(),
+            direct_dependents: column(entries, |x| &x.direct_dependents),
+            transitive_dependents: column(entries, |x| &x.transitive_dependents),
+            total_crates: column(entries, |x| &x.total_crates),
+            normal_dependents: column(entries, |x| &x.normal_dependents),
+            dev_dependents: column(entries, |x| &x.dev_dependents),
+            build_dependents: column(entries, |x| &x.build_dependents),
+            target_direct_dependents: column(entries, |x| &x.target_direct_dependents),
+            target_transitive_dependents: column(entries, |x| &x.target_transitive_dependents),
+            yanked_versions: column(entries, |x| &x.yanked_versions),
+            total_versions: column(entries, |x| &x.total_versions),
+            compat_dependents: column(entries, |x| &x.compat_dependents),
+            latest_dependents: column(entries, |x| &x.latest_dependents),
+            added_dependents: column(entries, |x| &x.added_dependents),
+            removed_dependents: column(entries, |x| &x.removed_dependents),
+        }
+    }
+
+    fn into_entries(self) -> Vec<Entry> {
+        let mut target_direct_dependents = self.target_direct_dependents.into_iter();
+        let mut target_transitive_dependents = self.target_transitive_dependents.into_iter();
+        let mut compat_dependents = self.compat_dependents.into_iter();
+        let mut added_dependents = self.added_dependents.into_iter();
+        let mut removed_dependents = self.removed_dependents.into_iter();
+
+        let mut ret = Vec::with_capacity(self.time.len());
+        for (i, time) in self.time.into_iter().enumerate() {
+            ret.push(Entry {
+                time: Utc.timestamp_opt(time, 0).unwrap(),
+                direct_dependents: self.direct_dependents[i],
+                transitive_dependents: self.transitive_dependents[i],
+                total_crates: self.total_crates[i],
+                normal_dependents: self.normal_dependents[i],
+                dev_dependents: self.dev_dependents[i],
+                build_dependents: self.build_dependents[i],
+                target_direct_dependents: target_direct_dependents.next().unwrap_or_default(),
+                target_transitive_dependents: target_transitive_dependents
+                    .next()
+                    .unwrap_or_default(),
+                yanked_versions: self.yanked_versions[i],
+                total_versions: self.total_versions[i],
+                compat_dependents: compat_dependents.next().unwrap_or_default(),
+                latest_dependents: self.latest_dependents[i],
+                added_dependents: added_dependents.next().unwrap_or_default(),
+                removed_dependents: removed_dependents.next().unwrap_or_default(),
+            });
+        }
+        ret
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Entry {
     #[serde(with = "ts_seconds")]
     pub time: DateTime<Utc>,
     pub direct_dependents: u64,
     pub transitive_dependents: u64,
     pub total_crates: u64,
+    pub normal_dependents: u64,
+    pub dev_dependents: u64,
+    pub build_dependents: u64,
+    /// Direct dependents on each target of `Db::targets`
+    pub target_direct_dependents: Vec<u64>,
+    /// Transitive dependents on each target of `Db::targets`
+    pub target_transitive_dependents: Vec<u64>,
+    pub yanked_versions: u64,
+    pub total_versions: u64,
+    /// Direct dependents of each semver compatible range like `1` or `0.9`, sorted by the range
+    pub compat_dependents: Vec<(String, u64)>,
+    /// Direct dependents whose requirement matches the newest non-yanked release
+    pub latest_dependents: u64,
+    /// Direct dependents added since the previous entry of the crate
+    pub added_dependents: Vec<String>,
+    /// Direct dependents removed since the previous entry of the crate
+    pub removed_dependents: Vec<String>,
+}
+
+impl Entry {
+    pub fn new(time: DateTime<Utc>, total_crates: u64, targets: usize) -> Self {
+        Entry {
+            time,
+            direct_dependents: 0,
+            transitive_dependents: 0,
+            total_crates,
+            normal_dependents: 0,
+            dev_dependents: 0,
+            build_dependents: 0,
+            target_direct_dependents: vec![0; targets],
+            target_transitive_dependents: vec![0; targets],
+            yanked_versions: 0,
+            total_versions: 0,
+            compat_dependents: Vec::new(),
+            latest_dependents: 0,
+            added_dependents: Vec::new(),
+            removed_dependents: Vec::new(),
+        }
+    }
+
+    pub fn dependents(&self, metric: Metric) -> u64 {
+        match metric {
+            Metric::Direct => self.direct_dependents,
+            Metric::Transitive => self.transitive_dependents,
+            Metric::Normal => self.normal_dependents,
+            Metric::Dev => self.dev_dependents,
+            Metric::Build => self.build_dependents,
+            Metric::TargetDirect(i) => self.target_direct_dependents.get(i).copied().unwrap_or(0),
+            Metric::TargetTransitive(i) => self
+                .target_transitive_dependents
+                .get(i)
+                .copied()
+                .unwrap_or(0),
+            Metric::YankedShare => self.yanked_versions,
+        }
+    }
+
+    /// Share of yanked versions in all versions of the crate
+    pub fn yanked_share(&self) -> f64 {
+        if self.total_versions == 0 {
+            0.0
+        } else {
+            self.yanked_versions as f64 / self.total_versions as f64
+        }
+    }
+
+    fn has_same_dependents(&self, other: &Entry) -> bool {
+        self.direct_dependents == other.direct_dependents
+            && self.transitive_dependents == other.transitive_dependents
+            && self.normal_dependents == other.normal_dependents
+            && self.dev_dependents == other.dev_dependents
+            && self.build_dependents == other.build_dependents
+            && self.target_direct_dependents == other.target_direct_dependents
+            && self.target_transitive_dependents == other.target_transitive_dependents
+            && self.yanked_versions == other.yanked_versions
+            && self.total_versions == other.total_versions
+            && self.compat_dependents == other.compat_dependents
+            && self.latest_dependents == other.latest_dependents
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    /// Direct dependents of all kinds
+    Direct,
+    Transitive,
+    Normal,
+    Dev,
+    Build,
+    /// Direct dependents on the target of the index in `Db::targets`
+    TargetDirect(usize),
+    TargetTransitive(usize),
+    /// Share of yanked versions, which is ranked by the number of yanked versions
+    YankedShare,
+}
+
+/// Dependents gained, and dependents lost with the crate which they switched to
+pub type Diff = (Vec<String>, Vec<(String, Option<String>)>);
+
+/// Dependents switched between two crates at a snapshot
+#[derive(Debug)]
+pub struct Migration {
+    pub time: DateTime<Utc>,
+    /// Dependents which dropped the first crate and added the second one
+    pub forward: u64,
+    /// Dependents which dropped the second crate and added the first one
+    pub backward: u64,
+    /// Direct dependents of each crate at the snapshot
+    pub dependents: (u64, u64),
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Corrupted {
+        chunk: String,
+        expected: String,
+        actual: String,
+    },
+    Version(u32),
+    /// Chunk which is not fetched yet or removed
+    NotFound(String),
+    /// Corrupted chunks which can't be fetched from the source
+    Unrepairable {
+        chunks: Vec<String>,
+        source: String,
+    },
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Corrupted {
+                chunk,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "db chunk {} is corrupted: expected hash {}, actual hash {}",
+                chunk, expected, actual
+            ),
+            DbError::Version(version) => write!(
+                f,
+                "db version {} is not supported ( supported version: {} )",
+                version, DB_VERSION
+            ),
+            DbError::NotFound(chunk) => write!(f, "db chunk {} is not found", chunk),
+            DbError::Unrepairable { chunks, source } => write!(
+                f,
+                "db chunk {} can't be fetched from {}, so db should be rebuilt by `--update` into an empty directory",
+                chunks.join(", "),
+                source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+pub struct UpdateOption {
+    branch: Option<String>,
+    sparse: Option<String>,
+    crate_list: Option<PathBuf>,
+    index_path: Option<PathBuf>,
+    index_url: Option<String>,
+    checkpoint: Option<(PathBuf, usize)>,
+    jobs: Option<usize>,
+    dev_transitive: bool,
+    targets: Vec<String>,
+    include_yanked: bool,
+    features: FeaturePolicy,
+}
+
+impl UpdateOption {
+    pub fn new() -> Self {
+        UpdateOption {
+            branch: None,
+            sparse: None,
+            crate_list: None,
+            index_path: None,
+            index_url: None,
+            checkpoint: None,
+            jobs: None,
+            dev_transitive: false,
+            targets: Vec::new(),
+            include_yanked: false,
+            features: FeaturePolicy::Default,
+        }
+    }
+
+    pub fn branch(mut self, branch: Option<String>) -> Self {
+        self.branch = branch;
+        self
+    }
+
+    pub fn sparse(mut self, sparse: Option<String>) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    pub fn crate_list(mut self, crate_list: Option<PathBuf>) -> Self {
+        self.crate_list = crate_list;
+        self
+    }
+
+    pub fn index_path(mut self, index_path: Option<PathBuf>) -> Self {
+        self.index_path = index_path;
+        self
+    }
+
+    pub fn index_url(mut self, index_url: Option<String>) -> Self {
+        self.index_url = index_url;
+        self
+    }
+
+    /// Number of threads to analyze index ( the number of CPUs by default )
+    pub fn jobs(mut self, jobs: Option<usize>) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Count dev-dependencies of dependencies as transitive dependents like the older versions
+    pub fn dev_transitive(mut self, dev_transitive: bool) -> Self {
+        self.dev_transitive = dev_transitive;
+        self
+    }
+
+    /// Resolve dependencies to yanked versions too
+    pub fn include_yanked(mut self, include_yanked: bool) -> Self {
+        self.include_yanked = include_yanked;
+        self
+    }
+
+    /// Features enabled on root crates
+    pub fn features(mut self, features: FeaturePolicy) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Target triples to count dependents on each target additionally
+    pub fn targets(mut self, targets: Vec<String>) -> Self {
+        self.targets = targets;
+        self
+    }
+
+    /// Whether the snapshot has the crates of the crate list only
+    fn partial(&self) -> bool {
+        self.sparse.as_deref().is_some_and(is_http)
+    }
+
+    /// Save db into `dir` every `interval` revisions
+    pub fn checkpoint(mut self, dir: PathBuf, interval: usize) -> Self {
+        self.checkpoint = if interval == 0 {
+            None
+        } else {
+            Some((dir, interval))
+        };
+        self
+    }
 }
 
 impl Db {
@@ -44,104 +482,519 @@ impl Db {
         Db {
             update: Utc.timestamp_opt(0, 0).unwrap(),
             map: HashMap::new(),
+            targets: Vec::new(),
+            features: FeaturePolicy::Default,
+            dev_transitive: false,
+            include_yanked: false,
+            partial: false,
+            edges_since: None,
+            current: HashMap::new(),
+            base: Utc.timestamp_opt(0, 0).unwrap(),
         }
     }
 
-    pub fn load<T: AsRef<Path>>(dir: T) -> Result<Db, Error> {
+    /// Load db of the current or older versions
+    ///
+    /// If `crates` is given, only the chunks containing them are read, and the other crates are
+    /// not in `map`. Older versions have no index of crates, so all chunks are read.
+    pub fn load<T: AsRef<Path>>(dir: T, crates: Option<&[String]>) -> Result<Db, Error> {
+        commit_staging(dir.as_ref())?;
+
         let path = dir.as_ref().join("db.json");
+        if !path.exists() && dir.as_ref().join("db.gz").exists() {
+            let mut db = legacy::load_gz(dir.as_ref())?;
+            db.retain(crates);
+            return Ok(db);
+        }
+
         let mut file = File::open(&path)?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
         let header: DbHeader = serde_json::from_str(&String::from_utf8(buf)?)?;
+        if header.version > DB_VERSION {
+            return Err(DbError::Version(header.version).into());
+        }
 
         let mut db = Db {
             update: header.update,
             map: HashMap::new(),
+            targets: header.targets.clone(),
+            features: header.features,
+            // db_v3 counted dev-dependencies of dependencies and resolved yanked versions
+            dev_transitive: header.dev_transitive || header.version < DB_VERSION,
+            include_yanked: header.include_yanked || header.version < DB_VERSION,
+            partial: header.partial,
+            edges_since: header.edges_since,
+            current: HashMap::new(),
+            base: header.base,
         };
 
-        for i in 0..header.hash.len() {
-            let path = dir.as_ref().join(format!("db{}", i));
-            let mut file = File::open(path)?;
-            let mut buf = Vec::new();
-            file.read_to_end(&mut buf)?;
-            let mut buf = buf.as_slice();
-            let mut deserializer = Deserializer::new(&mut buf);
-            let chunk = DbChunk::deserialize(&mut deserializer)?;
+        if header.version < DB_VERSION {
+            for (i, h) in header.hash.iter().enumerate() {
+                for (name, entry) in legacy::read_chunk_of(dir.as_ref(), i, h, header.version)? {
+                    db.map.entry(name).or_default().push(entry);
+                }
+            }
+            db.retain(crates);
+            return Ok(db);
+        }
 
-            for (name, entry) in chunk.data {
-                db.map
-                    .entry(name)
-                    .and_modify(|e| e.push(entry.clone()))
-                    .or_insert(vec![entry]);
+        // base chunks are before recent chunks, so entries are pushed in time order
+        let wanted = |name: &String| crates.is_none_or(|x| x.contains(name));
+        for i in header.chunks(crates) {
+            if i < header.index.len() {
+                for columns in read_chunk::<DbChunk>(dir.as_ref(), i, &header.hash[i])?.crates {
+                    if wanted(&columns.name) {
+                        db.map.insert(columns.name.clone(), columns.into_entries());
+                    }
+                }
+            } else {
+                for (name, entry) in
+                    read_chunk::<RecentChunk>(dir.as_ref(), i, &header.hash[i])?.data
+                {
+                    if wanted(&name) {
+                        db.map.entry(name).or_default().push(entry);
+                    }
+                }
             }
         }
 
         Ok(db)
     }
 
-    pub fn save<T: AsRef<Path>>(&self, dir: T) -> Result<(), Error> {
-        let mut map: Vec<_> = self.map.iter().collect();
-        map.sort_by_key(|x| x.0);
+    /// Remove crates other than `crates` from `map`
+    fn retain(&mut self, crates: Option<&[String]>) {
+        if let Some(crates) = crates {
+            self.map.retain(|name, _| crates.contains(name));
+        }
+    }
 
-        let mut data = Vec::new();
-        for (k, v) in map {
-            for e in v {
-                data.push((k.to_owned(), e.to_owned()));
+    /// Upgrade db of older versions in the directory to the current version
+    pub fn migrate<T: AsRef<Path>>(dir: T) -> Result<(), Error> {
+        let mut db = Db::load(dir.as_ref(), None)?;
+        db.save(dir.as_ref())?;
+
+        // db.gz of db_v1 and db_v2 is replaced by db.json and chunks
+        for name in ["db.gz", "db.gz.sha256"] {
+            let path = dir.as_ref().join(name);
+            if path.exists() {
+                fs::remove_file(path)?;
             }
         }
-        data.sort_by_key(|x| x.1.time);
 
-        let mut hashes = Vec::new();
-        let mut i = 0;
-        while data.len() > 1000000 {
-            let rest = data.split_off(1000000);
+        println!(
+            "Migrate DB: {} is upgraded to version {}",
+            dir.as_ref().to_string_lossy(),
+            DB_VERSION
+        );
+        Ok(())
+    }
+
+    /// Repair corrupted chunks, which contain `crates` or all chunks if `crates` is None
+    ///
+    /// A corrupted chunk is fetched from `source` if it has the same hash there.
+    /// Otherwise `DbError::Unrepairable` is returned, and the chunks are left as they are.
+    pub fn repair<T: AsRef<Path>>(
+        dir: T,
+        source: &str,
+        crates: Option<&[String]>,
+    ) -> Result<(), Error> {
+        commit_staging(dir.as_ref())?;
+
+        let path = dir.as_ref().join("db.json");
+        let header: DbHeader = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if header.version != DB_VERSION {
+            return Err(DbError::Version(header.version).into());
+        }
 
+        let mut corrupted = Vec::new();
+        for i in header.chunks(crates) {
             let path = dir.as_ref().join(format!("db{}", i));
-            let hash = write_chunk(&path, data.clone())?;
-            hashes.push(hash);
+            if !path.exists() || hash_file(&path)? != header.hash[i] {
+                corrupted.push(i);
+            }
+        }
+        if corrupted.is_empty() {
+            return Ok(());
+        }
+
+        let source_header = read_source(source, "db.json")
+            .ok()
+            .and_then(|x| serde_json::from_slice::<DbHeader>(&x).ok());
+
+        let mut rest = Vec::new();
+        for i in corrupted {
+            let name = format!("db{}", i);
+            let fetched = match &source_header {
+                Some(x) if x.hash.get(i) == Some(&header.hash[i]) => read_source(source, &name)
+                    .ok()
+                    .filter(|buf| format!("{:x}", Sha256::digest(buf)) == header.hash[i]),
+                _ => None,
+            };
+
+            if let Some(buf) = fetched {
+                println!("Repair DB: {} is fetched from {}", name, source);
+                write_file(&dir.as_ref().join(&name), &buf)?;
+            } else {
+                rest.push(i);
+            }
+        }
+
+        // a base chunk has the whole history of its crates, so the db can't be truncated to rebuild it
+        if !rest.is_empty() {
+            return Err(DbError::Unrepairable {
+                chunks: rest.iter().map(|i| format!("db{}", i)).collect(),
+                source: String::from(source),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Check that dependents at the date are known by `edges_since`
+    pub fn check_edges(&self, date: NaiveDate) -> Result<(), Error> {
+        match self.edges_since {
+            Some(x) if date >= x.date_naive() => Ok(()),
+            Some(x) => Err(anyhow!(
+                "db has dependents since {}, but {} is given",
+                x.date_naive(),
+                date
+            )),
+            None => Err(anyhow!(
+                "db has no dependents, so it should be updated by the current version"
+            )),
+        }
+    }
+
+    /// Direct dependents of the crate at the date, which should be checked by `check_edges`
+    pub fn dependents(&self, name: &str, date: NaiveDate) -> BTreeSet<String> {
+        let mut ret = BTreeSet::new();
+        for entry in self.map.get(name).map(|x| x.as_slice()).unwrap_or_default() {
+            if entry.time.date_naive() > date {
+                break;
+            }
+            for x in &entry.removed_dependents {
+                ret.remove(x);
+            }
+            ret.extend(entry.added_dependents.iter().cloned());
+        }
+        ret
+    }
 
-            data = rest;
-            i += 1;
+    /// Dependents gained and lost between the dates, and what the lost dependents switched to
+    ///
+    /// The switched crate is the dependency added by the lost dependent, which is added by the
+    /// most lost dependents.
+    pub fn diff(&self, name: &str, from: NaiveDate, to: NaiveDate) -> Result<Diff, Error> {
+        self.check_edges(from)?;
+        self.check_edges(to)?;
+
+        let old = self.dependents(name, from);
+        let new = self.dependents(name, to);
+        let gained = new.difference(&old).cloned().collect();
+        let lost: BTreeSet<&str> = old.difference(&new).map(|x| x.as_str()).collect();
+
+        // dependencies added by the lost dependents between the dates
+        let mut added: HashMap<&str, Vec<&str>> = HashMap::new();
+        let mut count: HashMap<&str, usize> = HashMap::new();
+        for x in self.map.keys() {
+            if x == name {
+                continue;
+            }
+            let old = self.dependents(x, from);
+            for dependent in self.dependents(x, to).difference(&old) {
+                if let Some(&dependent) = lost.get(dependent.as_str()) {
+                    added.entry(dependent).or_default().push(x);
+                    *count.entry(x).or_default() += 1;
+                }
+            }
         }
 
-        let path = dir.as_ref().join(format!("db{}", i));
-        let hash = write_chunk(&path, data.clone())?;
-        hashes.push(hash);
+        let lost = lost
+            .into_iter()
+            .map(|dependent| {
+                let switched = added.get(dependent).and_then(|x| {
+                    x.iter()
+                        .max_by(|a, b| count[*a].cmp(&count[*b]).then(b.cmp(a)))
+                        .map(|x| String::from(*x))
+                });
+                (String::from(dependent), switched)
+            })
+            .collect();
+
+        Ok((gained, lost))
+    }
+
+    /// Dependents switched between the crates at each snapshot which either of them changed
+    ///
+    /// Snapshots before `edges_since` have no switch because their dependents are unknown.
+    pub fn migration(&self, from: &str, to: &str) -> Vec<Migration> {
+        let empty = Vec::new();
+        let from = self.map.get(from).unwrap_or(&empty);
+        let to = self.map.get(to).unwrap_or(&empty);
+
+        let mut snapshots: BTreeMap<DateTime<Utc>, (Option<&Entry>, Option<&Entry>)> =
+            BTreeMap::new();
+        for entry in from {
+            snapshots.entry(entry.time).or_default().0 = Some(entry);
+        }
+        for entry in to {
+            snapshots.entry(entry.time).or_default().1 = Some(entry);
+        }
+
+        // deltas of entries are from the previous snapshot, so both changes at the same time are switches
+        let switched = |removed: &Entry, added: &Entry| {
+            removed
+                .removed_dependents
+                .iter()
+                .filter(|x| added.added_dependents.contains(x))
+                .count() as u64
+        };
+
+        let mut ret = Vec::new();
+        let mut dependents = (0, 0);
+        for (time, (from, to)) in snapshots {
+            if let Some(x) = from {
+                dependents.0 = x.direct_dependents;
+            }
+            if let Some(x) = to {
+                dependents.1 = x.direct_dependents;
+            }
+            let (forward, backward) = match (from, to) {
+                (Some(from), Some(to)) => (switched(from, to), switched(to, from)),
+                _ => (0, 0),
+            };
+            ret.push(Migration {
+                time,
+                forward,
+                backward,
+                dependents,
+            });
+        }
+        ret
+    }
+
+    pub fn save<T: AsRef<Path>>(&mut self, dir: T) -> Result<(), Error> {
+        fs::create_dir_all(dir.as_ref())?;
+        commit_staging(dir.as_ref())?;
+
+        let mut map: Vec<_> = self.map.iter().collect();
+        map.sort_by_key(|x| x.0);
+
+        // recent entries are merged into base chunks when they grow, which changes all chunks
+        let recent = map
+            .iter()
+            .flat_map(|x| x.1)
+            .filter(|x| x.time > self.base)
+            .count();
+        if recent > RECENT_ENTRIES {
+            self.base = self.update;
+        }
+        let base = self.base;
+
+        let staging = create_staging(dir.as_ref())?;
+
+        // base chunks are sorted by crate name, so the chunk of a crate is found by the first names
+        let mut hashes = Vec::new();
+        let mut index = Vec::new();
+        let mut chunk = Vec::new();
+        let mut entries = 0;
+        for (k, v) in &map {
+            let n = v.partition_point(|x| x.time <= base);
+            if n == 0 {
+                continue;
+            }
+            chunk.push(Columns::new(k, &v[..n]));
+            entries += n;
+
+            if entries >= CHUNK_ENTRIES {
+                index.push(chunk[0].name.clone());
+                let path = staging.join(format!("db{}", hashes.len()));
+                let crates = std::mem::take(&mut chunk);
+                hashes.push(write_chunk(&path, &DbChunk { crates })?);
+                entries = 0;
+            }
+        }
+        if !chunk.is_empty() {
+            index.push(chunk[0].name.clone());
+            let path = staging.join(format!("db{}", hashes.len()));
+            hashes.push(write_chunk(&path, &DbChunk { crates: chunk })?);
+        }
+
+        // recent chunks are split from the oldest, so the older chunks are not changed by update
+        let mut data: Vec<(String, Entry)> = Vec::new();
+        for (k, v) in &map {
+            let n = v.partition_point(|x| x.time <= base);
+            data.extend(v[n..].iter().map(|e| (k.to_string(), e.clone())));
+        }
+        data.sort_by_key(|x| x.1.time);
+        for data in data.chunks(CHUNK_ENTRIES) {
+            let path = staging.join(format!("db{}", hashes.len()));
+            let data = data.to_vec();
+            hashes.push(write_chunk(&path, &RecentChunk { data })?);
+        }
 
         let header = DbHeader {
+            version: DB_VERSION,
             update: self.update.to_owned(),
             hash: hashes,
+            targets: self.targets.clone(),
+            features: self.features,
+            dev_transitive: self.dev_transitive,
+            include_yanked: self.include_yanked,
+            partial: self.partial,
+            edges_since: self.edges_since,
+            index,
+            base,
         };
+        verify_chunks(dir.as_ref(), Some(&staging), &header, &header.chunks(None))?;
+
         let encoded: Vec<u8> = serde_json::to_string(&header)?.into_bytes();
-        let path = dir.as_ref().join("db.json");
-        let mut file = File::create(path)?;
-        file.write_all(&encoded)?;
-        file.flush()?;
+        write_file(&staging.join("db.json"), &encoded)?;
+        commit_staging(dir.as_ref())?;
 
         Ok(())
     }
 
-    pub fn update(&mut self, branch: Option<String>) -> Result<(), Error> {
-        let url = "https://github.com/rust-lang/crates.io-index.git";
-        let dir = tempdir()?;
-
-        let repo = Repository::clone(url, &dir)?;
-        repo.checkout_head(None)?;
-        if let Some(branch) = branch {
-            let master = repo.find_branch(&format!("origin/{}", branch), BranchType::Remote)?;
-            let master = master.get().peel_to_commit()?;
-            let master = repo.find_object(master.id(), None)?;
-            repo.reset(&master, ResetType::Hard, None)?;
+    pub fn update(&mut self, option: &UpdateOption) -> Result<(), Error> {
+        // per-target dependents of entries are indexed by the targets of db
+        if self.map.is_empty() {
+            self.targets = option.targets.clone();
+            self.features = option.features;
+            self.dev_transitive = option.dev_transitive;
+            self.include_yanked = option.include_yanked;
+            self.partial = option.partial();
+        } else if self.targets != option.targets {
+            return Err(anyhow!(
+                "db is built with targets [{}], but [{}] is given",
+                self.targets.join(", "),
+                option.targets.join(", ")
+            ));
+        } else if self.features != option.features {
+            return Err(anyhow!(
+                "db is built with feature policy {}, but {} is given",
+                self.features,
+                option.features
+            ));
+        } else if self.dev_transitive != option.dev_transitive {
+            return Err(anyhow!(
+                "db is built {} `--dev-transitive`, but it is {}given",
+                if self.dev_transitive {
+                    "with"
+                } else {
+                    "without"
+                },
+                if option.dev_transitive { "" } else { "not " }
+            ));
+        } else if self.include_yanked != option.include_yanked {
+            return Err(anyhow!(
+                "db is built {} `--include-yanked`, but it is {}given",
+                if self.include_yanked {
+                    "with"
+                } else {
+                    "without"
+                },
+                if option.include_yanked { "" } else { "not " }
+            ));
+        } else if self.partial != option.partial() {
+            // dependents which are not in the crate list would be removed from the whole index
+            let source = |partial| {
+                if partial {
+                    "a crate list of sparse HTTP index"
+                } else {
+                    "the whole index"
+                }
+            };
+            return Err(anyhow!(
+                "db is built from {}, but {} is given",
+                source(self.partial),
+                source(option.partial())
+            ));
         }
+        let targets = option
+            .targets
+            .iter()
+            .map(|x| Target::new(x))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // entries have only deltas of dependents, so the last dependents are restored from them
+        let date = self.update.date_naive();
+        self.current = self
+            .map
+            .keys()
+            .map(|name| (name.clone(), self.dependents(name, date)))
+            .collect();
+
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(option.jobs.unwrap_or(0))
+            .build()?;
+
+        pool.install(|| {
+            if let Some(sparse) = &option.sparse {
+                self.update_sparse(sparse, option, &targets)
+            } else {
+                self.update_git(option, &targets)
+            }
+        })
+    }
+
+    fn update_git(&mut self, option: &UpdateOption, targets: &[Target]) -> Result<(), Error> {
+        let url = option
+            .index_url
+            .as_deref()
+            .unwrap_or("https://github.com/rust-lang/crates.io-index.git");
+
+        // the clone in tempdir is removed at drop
+        let tmp;
+        let dir = if let Some(path) = &option.index_path {
+            path.clone()
+        } else {
+            tmp = tempdir()?;
+            tmp.path().to_path_buf()
+        };
+
+        let repo = if let Ok(repo) = Repository::open(&dir) {
+            println!("Fetch index: {}", dir.to_string_lossy());
+            // the existing clone may have been taken from a mirror, so fetch it from origin
+            // unless the index URL is given explicitly
+            {
+                let mut remote = if option.index_url.is_some() {
+                    repo.remote_anonymous(url)?
+                } else {
+                    repo.find_remote("origin")?
+                };
+                remote.fetch(&["+refs/heads/*:refs/remotes/origin/*"], None, None)?;
+            }
+            repo
+        } else {
+            println!("Clone index: {}", url);
+            RepoBuilder::new().bare(true).clone(url, &dir)?
+        };
+
+        let head = if let Some(branch) = &option.branch {
+            let branch = repo.find_branch(&format!("origin/{}", branch), BranchType::Remote)?;
+            branch.get().peel_to_commit()?.id()
+        } else {
+            repo.revparse_single("refs/remotes/origin/HEAD")
+                .or_else(|_| repo.revparse_single("refs/remotes/origin/master"))
+                .or_else(|_| repo.revparse_single("HEAD"))?
+                .peel_to_commit()?
+                .id()
+        };
 
         let mut revs = Vec::new();
         let mut revwalk = repo.revwalk()?;
-        revwalk.push_head()?;
+        revwalk.set_sorting(Sort::TIME)?;
+        revwalk.push(head)?;
         let mut last = Utc.timestamp_opt(0, 0).unwrap();
         for oid in revwalk {
             let commit = repo.find_commit(oid?)?;
             let time = Utc.timestamp_opt(commit.time().seconds(), 0).unwrap();
-            if last.date_naive() != time.date_naive() && time > self.update {
+            if time <= self.update {
+                break;
+            }
+            if last.date_naive() != time.date_naive() {
                 revs.push((time, commit.id()));
                 last = time;
             }
@@ -149,288 +1002,1076 @@ impl Db {
 
         revs.reverse();
 
+        let mut index = GitIndex::with_path(&dir, url)?;
         let total = revs.len();
         for (i, (time, id)) in revs.iter().enumerate() {
             println!("Update DB: {} {} ( {} / {} )", time, id, i + 1, total);
-            let obj = repo.find_object(id.clone(), None)?;
-            repo.reset(&obj, ResetType::Hard, None)?;
+            index.set_commit_from_refspec(&id.to_string())?;
 
-            let index = GitIndex::with_path(&dir.path(), crates_index::git::URL)?;
-            let mut crates = HashMap::new();
-            for c in index.crates() {
-                crates.insert(String::from(c.name()), c);
+            let crates = index
+                .crates_parallel()
+                .map(|c| c.map(|c| (String::from(c.name()), c)))
+                .collect::<Result<HashMap<_, _>, _>>()?;
+
+            self.update_snapshot(*time, &crates, option, targets);
+            self.update = *time;
+
+            if let Some((dir, interval)) = &option.checkpoint {
+                if (i + 1) % interval == 0 && i + 1 < total {
+                    println!("Checkpoint DB: {}", dir.to_string_lossy());
+                    self.save(dir)?;
+                }
             }
+        }
 
-            let n = crates.len();
-            let mut names: Vec<String> = crates.keys().cloned().collect();
-            names.sort();
-            let id_of: HashMap<&str, u32> = names
-                .iter()
-                .enumerate()
-                .map(|(i, n)| (n.as_str(), i as u32))
-                .collect();
-            let crates_by_id: Vec<&Crate> =
-                names.iter().map(|nm| &crates[nm.as_str()]).collect();
-
-            let mut deps: HashMap<String, (u64, u64)> = HashMap::new();
-            let mut cache: Vec<Option<HashSet<u32>>> = vec![None; n];
-            for root_id in 0..n as u32 {
-                let c = crates_by_id[root_id as usize];
-                let enabled_features = [String::from("default")];
-
-                for dep in gather_dependencies(c, &VersionReq::STAR, &enabled_features) {
-                    let dn = dep.crate_name();
-                    deps.entry(String::from(dn)).or_insert((0, 0)).0 += 1;
+        Ok(())
+    }
+
+    fn update_sparse(
+        &mut self,
+        location: &str,
+        option: &UpdateOption,
+        targets: &[Target],
+    ) -> Result<(), Error> {
+        // sparse index has no history, so only the snapshot of today can be added
+        let time = Utc::now();
+        if time.date_naive() <= self.update.date_naive() {
+            println!(
+                "Update DB: snapshot of {} already exists",
+                time.date_naive()
+            );
+            return Ok(());
+        }
+
+        let crates = if is_http(location) {
+            read_sparse_http(location, option.crate_list.as_deref())?
+        } else {
+            let mut crates = HashMap::new();
+            read_sparse_dir(Path::new(location), &mut crates)?;
+            crates
+        };
+
+        // empty snapshot would remove all dependents, and the snapshot of today can't be fixed
+        if crates.is_empty() {
+            return Err(anyhow!("no crate is found in sparse index {}", location));
+        }
+
+        println!("Update DB: {} {} ( 1 / 1 )", time, location);
+        self.update_snapshot(time, &crates, option, targets);
+        self.update = time;
+
+        Ok(())
+    }
+
+    fn update_snapshot(
+        &mut self,
+        time: DateTime<Utc>,
+        crates: &HashMap<String, Crate>,
+        option: &UpdateOption,
+        targets: &[Target],
+    ) {
+        let n = crates.len();
+        let mut names: Vec<String> = crates.keys().cloned().collect();
+        names.sort();
+        let id_of: HashMap<&str, u32> = names
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.as_str(), i as u32))
+            .collect();
+        let crates_by_id: Vec<&Crate> = names.iter().map(|nm| &crates[nm.as_str()]).collect();
+
+        let graph = Graph::new(&crates_by_id, &id_of)
+            .dev_transitive(option.dev_transitive)
+            .include_yanked(option.include_yanked)
+            .features(option.features);
+
+        // direct dependencies are independent of each other, so they can be gathered in parallel
+        let direct: Vec<Vec<Dependency>> = (0..n as u32)
+            .into_par_iter()
+            .map(|id| graph.direct(id))
+            .collect();
+
+        let total_crates = n as u64;
+        let mut deps: HashMap<String, Entry> = HashMap::new();
+        let mut edges: HashMap<&str, BTreeSet<String>> = HashMap::new();
+        for (id, dependencies) in direct.iter().enumerate() {
+            // a crate may be depended as some kinds or versions, but it is counted once for each
+            let mut kinds: HashMap<&str, HashSet<DependencyKind>> = HashMap::new();
+            let mut compats: HashMap<&str, HashSet<String>> = HashMap::new();
+            let mut latests: HashSet<&str> = HashSet::new();
+            for dep in dependencies {
+                kinds
+                    .entry(dep.crate_name())
+                    .or_default()
+                    .insert(dep.kind());
+                if let Some(version) = graph.resolved(dep) {
+                    compats
+                        .entry(dep.crate_name())
+                        .or_default()
+                        .insert(compat_range(version));
                 }
+                if graph.matches_latest(dep) {
+                    latests.insert(dep.crate_name());
+                }
+            }
 
-                let mut trace = HashSet::<u32>::new();
-                trace.insert(root_id);
-                let (transitive, looped) = gather_transitive(
-                    root_id,
-                    &VersionReq::STAR,
-                    &enabled_features,
-                    &mut trace,
-                    &crates_by_id,
-                    &id_of,
-                    &mut cache,
-                );
-
-                // connect looped transitive
-                for &l in &looped {
-                    let l_cached = match &cache[l as usize] {
-                        Some(s) => s.clone(),
-                        None => continue,
-                    };
-                    for &t in &transitive {
-                        if let Some(t_cached) = cache[t as usize].as_mut() {
-                            if t_cached.contains(&l) {
-                                for &x in &l_cached {
-                                    t_cached.insert(x);
-                                }
-                            }
-                        }
+            for name in latests {
+                deps.entry(String::from(name))
+                    .or_insert_with(|| Entry::new(time, total_crates, targets.len()))
+                    .latest_dependents += 1;
+            }
+
+            for (name, compats) in compats {
+                let entry = deps
+                    .entry(String::from(name))
+                    .or_insert_with(|| Entry::new(time, total_crates, targets.len()));
+                for compat in compats {
+                    match entry.compat_dependents.iter_mut().find(|x| x.0 == compat) {
+                        Some(x) => x.1 += 1,
+                        None => entry.compat_dependents.push((compat, 1)),
                     }
                 }
+            }
+
+            for (name, kinds) in kinds {
+                edges.entry(name).or_default().insert(names[id].clone());
 
-                for &t in &transitive {
-                    deps.entry(names[t as usize].clone())
-                        .or_insert((0, 0))
-                        .1 += 1;
+                let entry = deps
+                    .entry(String::from(name))
+                    .or_insert_with(|| Entry::new(time, total_crates, targets.len()));
+                entry.direct_dependents += 1;
+                for kind in kinds {
+                    match kind {
+                        DependencyKind::Normal => entry.normal_dependents += 1,
+                        DependencyKind::Dev => entry.dev_dependents += 1,
+                        DependencyKind::Build => entry.build_dependents += 1,
+                    }
                 }
             }
+        }
 
-            let total_crates = n as u64;
-            for (name, (direct, transitive)) in &deps {
-                let entry = Entry {
-                    time: *time,
-                    direct_dependents: *direct,
-                    transitive_dependents: *transitive,
-                    total_crates,
-                };
-                if let Some(entries) = self.map.get_mut(name) {
-                    let last = &entries[entries.len() - 1];
-                    if last.direct_dependents != *direct
-                        || last.transitive_dependents != *transitive
-                    {
-                        (*entries).push(entry);
-                    }
-                } else {
-                    self.map.insert(name.clone(), vec![entry]);
+        for (id, transitive) in graph.transitive_dependents().into_iter().enumerate() {
+            if transitive > 0 {
+                deps.entry(names[id].clone())
+                    .or_insert_with(|| Entry::new(time, total_crates, targets.len()))
+                    .transitive_dependents = transitive;
+            }
+        }
+
+        for (i, target) in targets.iter().enumerate() {
+            let graph = Graph::new(&crates_by_id, &id_of)
+                .dev_transitive(option.dev_transitive)
+                .include_yanked(option.include_yanked)
+                .features(option.features)
+                .target(Some(target));
+
+            let direct: Vec<HashSet<&str>> = (0..n as u32)
+                .into_par_iter()
+                .map(|id| {
+                    graph
+                        .direct(id)
+                        .iter()
+                        .filter_map(|x| {
+                            id_of
+                                .get(x.crate_name())
+                                .map(|&id| names[id as usize].as_str())
+                        })
+                        .collect()
+                })
+                .collect();
+            for name in direct.iter().flatten() {
+                deps.entry(String::from(*name))
+                    .or_insert_with(|| Entry::new(time, total_crates, targets.len()))
+                    .target_direct_dependents[i] += 1;
+            }
+
+            for (id, transitive) in graph.transitive_dependents().into_iter().enumerate() {
+                if transitive > 0 {
+                    deps.entry(names[id].clone())
+                        .or_insert_with(|| Entry::new(time, total_crates, targets.len()))
+                        .target_transitive_dependents[i] = transitive;
                 }
             }
+        }
 
-            self.update = *time;
+        // dependents of the first snapshot are all added, so they are known since it
+        self.edges_since.get_or_insert(time);
+
+        // crates which lost all dependents have entries to remove them
+        for (name, dependents) in &self.current {
+            if !dependents.is_empty() && !deps.contains_key(name) {
+                deps.insert(name.clone(), Entry::new(time, total_crates, targets.len()));
+            }
         }
 
-        Ok(())
+        for (name, mut entry) in deps {
+            entry
+                .compat_dependents
+                .sort_by_key(|x| compat_range_key(&x.0));
+            if let Some(krate) = crates.get(&name) {
+                let versions = krate.versions();
+                entry.yanked_versions = versions.iter().filter(|x| x.is_yanked()).count() as u64;
+                entry.total_versions = versions.len() as u64;
+            }
+
+            let dependents = edges.remove(name.as_str()).unwrap_or_default();
+            let last = self.current.entry(name.clone()).or_default();
+            entry.added_dependents = dependents.difference(last).cloned().collect();
+            entry.removed_dependents = last.difference(&dependents).cloned().collect();
+            *last = dependents;
+
+            if let Some(entries) = self.map.get_mut(&name) {
+                let last = &entries[entries.len() - 1];
+                if !last.has_same_dependents(&entry)
+                    || !entry.added_dependents.is_empty()
+                    || !entry.removed_dependents.is_empty()
+                {
+                    entries.push(entry);
+                }
+            } else {
+                self.map.insert(name, vec![entry]);
+            }
+        }
     }
 
-    pub fn fetch<T: AsRef<Path>>(dir: T) -> Result<(), Error> {
-        let latest_header = reqwest::blocking::get(
-            "https://raw.githubusercontent.com/dalance/cargo-trend/master/db_v3/db.json",
-        )?
-        .text()?;
+    /// Fetch chunks which contain `crates`, or all chunks if `crates` is None
+    ///
+    /// The other chunks which differ from the new `db.json` are removed, and they are fetched when
+    /// they are required.
+    pub fn fetch<T: AsRef<Path>>(
+        dir: T,
+        source: &str,
+        crates: Option<&[String]>,
+    ) -> Result<(), Error> {
+        commit_staging(dir.as_ref())?;
 
+        let latest_header = String::from_utf8(read_source(source, "db.json")?)?;
         let header: DbHeader = serde_json::from_str(&latest_header)?;
-        let path = dir.as_ref().join("db.json");
-        let mut file = File::create(path)?;
-        file.write_all(latest_header.as_bytes())?;
-        file.flush()?;
+        if header.version != DB_VERSION {
+            return Err(DbError::Version(header.version))
+                .with_context(|| format!("failed to fetch db from {}", source));
+        }
+
+        // changed chunks are downloaded into staging, and moved after all chunks are verified
+        let staging = create_staging(dir.as_ref())?;
 
-        for (i, h) in header.hash.iter().enumerate() {
+        let chunks = header.chunks(crates);
+        for &i in &chunks {
+            let h = &header.hash[i];
             let path = dir.as_ref().join(format!("db{}", i));
             let fetch = if path.exists() {
-                let path = dir.as_ref().join(format!("db{}", i));
-                let mut file = File::open(&path)?;
-                let mut buf = Vec::new();
-                file.read_to_end(&mut buf)?;
-                let hash = format!("{:x}", Sha256::digest(&buf));
-                &hash != h
+                &hash_file(&path)? != h
             } else {
                 true
             };
 
             if fetch {
-                let mut res = reqwest::blocking::get(format!(
-                    "https://github.com/dalance/cargo-trend/raw/master/db_v3/db{}",
-                    i
-                ))?;
-                let mut buf = Vec::new();
-                res.read_to_end(&mut buf)?;
-                let path = dir.as_ref().join(format!("db{}", i));
-                let mut file = File::create(&path)?;
-                file.write_all(&buf)?;
-                file.flush()?;
+                let buf = read_source(source, &format!("db{}", i))?;
+                let hash = format!("{:x}", Sha256::digest(&buf));
+                if &hash != h {
+                    return Err(anyhow!(
+                        "hash mismatch of db{} from {}: expected {}, actual {}",
+                        i,
+                        source,
+                        h,
+                        hash
+                    ));
+                }
+                write_file(&staging.join(format!("db{}", i)), &buf)?;
             }
         }
 
+        verify_chunks(dir.as_ref(), Some(&staging), &header, &chunks)?;
+        write_file(&staging.join("db.json"), latest_header.as_bytes())?;
+        commit_staging(dir.as_ref())?;
+
         Ok(())
     }
 }
 
-fn write_chunk(path: &Path, data: Vec<(String, Entry)>) -> Result<String, Error> {
-    let chunk = DbChunk { data };
+/// Semver compatible range of the version like cargo ( `1.2.3` is `1`, `0.2.3` is `0.2` )
+fn compat_range(version: &Version) -> String {
+    if version.major > 0 {
+        format!("{}", version.major)
+    } else if version.minor > 0 {
+        format!("0.{}", version.minor)
+    } else {
+        format!("0.0.{}", version.patch)
+    }
+}
+
+/// Sort key of semver compatible range
+pub fn compat_range_key(range: &str) -> Vec<u64> {
+    range.split('.').map(|x| x.parse().unwrap_or(0)).collect()
+}
+
+fn read_chunk<T: DeserializeOwned>(dir: &Path, i: usize, hash: &str) -> Result<T, Error> {
+    let name = format!("db{}", i);
+    let path = dir.join(&name);
+    if !path.exists() {
+        return Err(DbError::NotFound(name).into());
+    }
+    let mut file =
+        File::open(&path).with_context(|| format!("failed to read {}", path.to_string_lossy()))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    let actual = format!("{:x}", Sha256::digest(&buf));
+    if actual != hash {
+        return Err(DbError::Corrupted {
+            chunk: name,
+            expected: String::from(hash),
+            actual,
+        }
+        .into());
+    }
+
+    let mut buf = buf.as_slice();
+    let mut deserializer = Deserializer::new(&mut buf);
+    Ok(T::deserialize(&mut deserializer)?)
+}
 
+fn write_chunk<T: Serialize>(path: &Path, chunk: &T) -> Result<String, Error> {
     let mut encoded = Vec::new();
     let mut serializer = Serializer::new(&mut encoded);
     chunk.serialize(&mut serializer)?;
-    let mut file = File::create(&path)?;
-    file.write_all(&encoded)?;
-    file.flush()?;
+    write_file(path, &encoded)?;
 
-    let mut file = File::open(&path)?;
+    hash_file(path)
+}
+
+/// Write a file through a temporary file, so the file is never seen half-written
+fn write_file(path: &Path, buf: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to read {}", path.to_string_lossy()))?;
     let mut buf = Vec::new();
     file.read_to_end(&mut buf)?;
     let hash = Sha256::digest(&buf);
     Ok(format!("{:x}", hash))
 }
 
-fn gather_transitive(
-    id: u32,
-    requirement: &VersionReq,
-    enabled_features: &[String],
-    trace: &mut HashSet<u32>,
-    crates_by_id: &[&Crate],
-    id_of: &HashMap<&str, u32>,
-    cache: &mut [Option<HashSet<u32>>],
-) -> (HashSet<u32>, HashSet<u32>) {
-    if let Some(cached) = &cache[id as usize] {
-        return (cached.clone(), HashSet::new());
-    }
-    let mut ret_looped = HashSet::new();
-    let mut ret_transitive = HashSet::new();
-    let c = crates_by_id[id as usize];
-    for dep in gather_dependencies(c, requirement, enabled_features) {
-        let dep_id = match id_of.get(dep.crate_name()) {
-            Some(&i) => i,
-            None => continue,
+/// Check `chunks` of `header`, which are in `staging` or `dir`
+fn verify_chunks(
+    dir: &Path,
+    staging: Option<&Path>,
+    header: &DbHeader,
+    chunks: &[usize],
+) -> Result<(), Error> {
+    for &i in chunks {
+        let h = &header.hash[i];
+        let name = format!("db{}", i);
+        let path = match staging {
+            Some(staging) if staging.join(&name).exists() => staging.join(&name),
+            _ => dir.join(&name),
         };
-        ret_transitive.insert(dep_id);
-        if !trace.contains(&dep_id) {
-            let mut dep_features: Vec<_> = dep.features().iter().cloned().collect();
-            if dep.has_default_features() {
-                dep_features.push(String::from("default"));
+        let hash = hash_file(&path)?;
+        if &hash != h {
+            return Err(DbError::Corrupted {
+                chunk: name,
+                expected: h.clone(),
+                actual: hash,
             }
+            .into());
+        }
+    }
+    Ok(())
+}
 
-            let requirement = match VersionReq::parse(dep.requirement()) {
-                Ok(x) => x,
-                Err(_) => continue,
-            };
+const STAGING: &str = ".staging";
 
-            trace.insert(dep_id);
-            let (transitive, looped) = gather_transitive(
-                dep_id,
-                &requirement,
-                &dep_features,
-                trace,
-                crates_by_id,
-                id_of,
-                cache,
-            );
-            trace.remove(&dep_id);
+fn create_staging(dir: &Path) -> Result<PathBuf, Error> {
+    let staging = dir.join(STAGING);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    Ok(staging)
+}
 
-            for l in looped {
-                ret_looped.insert(l);
-            }
-            for t in transitive {
-                ret_transitive.insert(t);
-            }
+/// Move the staged db into `dir`
+///
+/// `db.json` is written into staging only after all chunks are verified, so staging without it
+/// is discarded. Otherwise chunks are moved before `db.json`, and a commit interrupted halfway is
+/// completed by the next call.
+fn commit_staging(dir: &Path) -> Result<(), Error> {
+    let staging = dir.join(STAGING);
+    if !staging.exists() {
+        return Ok(());
+    }
+
+    let staged_header = staging.join("db.json");
+    if !staged_header.exists() {
+        fs::remove_dir_all(&staging)?;
+        return Ok(());
+    }
+
+    let header: DbHeader = serde_json::from_str(&fs::read_to_string(&staged_header)?)?;
+    for (i, h) in header.hash.iter().enumerate() {
+        let name = format!("db{}", i);
+        if staging.join(&name).exists() {
+            fs::rename(staging.join(&name), dir.join(&name))?;
+        } else if dir.join(&name).exists() && &hash_file(&dir.join(&name))? != h {
+            // chunks which are not fetched with the crates are obsolete, and they are fetched
+            // again when they are required
+            fs::remove_file(dir.join(&name))?;
+        }
+    }
+
+    // remove chunks which don't belong to the new header
+    let mut i = header.hash.len();
+    while dir.join(format!("db{}", i)).exists() {
+        fs::remove_file(dir.join(format!("db{}", i)))?;
+        i += 1;
+    }
+
+    fs::rename(&staged_header, dir.join("db.json"))?;
+    fs::remove_dir_all(&staging)?;
+    Ok(())
+}
+
+/// Read a file from HTTP URL, file:// URL or local directory
+fn read_source(source: &str, name: &str) -> Result<Vec<u8>, Error> {
+    if is_http(source) {
+        let url = format!("{}/{}", source.trim_end_matches('/'), name);
+        let mut res = reqwest::blocking::get(&url)?.error_for_status()?;
+        let mut buf = Vec::new();
+        res.read_to_end(&mut buf)?;
+        Ok(buf)
+    } else {
+        let dir = source.strip_prefix("file://").unwrap_or(source);
+        let path = Path::new(dir).join(name);
+        fs::read(&path).with_context(|| format!("failed to read {}", path.to_string_lossy()))
+    }
+}
+
+fn is_http(location: &str) -> bool {
+    location.starts_with("http://")
+        || location.starts_with("https://")
+        || location.starts_with("sparse+http")
+}
+
+fn read_sparse_dir(dir: &Path, crates: &mut HashMap<String, Crate>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // skip config.json and hidden directories like .cache or .git
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') || name == "config.json" {
+            continue;
+        }
+
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            read_sparse_dir(&path, crates)?;
         } else {
-            ret_looped.insert(dep_id);
+            let c = Crate::new(&path)
+                .with_context(|| format!("failed to parse {}", path.to_string_lossy()))?;
+            crates.insert(String::from(c.name()), c);
         }
     }
-    cache[id as usize] = Some(ret_transitive.clone());
-    (ret_transitive, ret_looped)
+    Ok(())
 }
 
-fn gather_dependencies(
-    krate: &Crate,
-    requirement: &VersionReq,
-    enabled_features: &[String],
-) -> Vec<Dependency> {
-    let krate = krate
-        .versions()
-        .iter()
-        .filter(|x| {
-            if let Ok(version) = Version::parse(x.version()) {
-                requirement.matches(&version)
-            } else {
-                false
-            }
+fn read_sparse_http(url: &str, crate_list: Option<&Path>) -> Result<HashMap<String, Crate>, Error> {
+    // sparse protocol can't enumerate crates, so the names are given by a file
+    let crate_list = crate_list
+        .ok_or_else(|| anyhow!("crate list is required to update from sparse HTTP index"))?;
+    let names = fs::read_to_string(crate_list)
+        .with_context(|| format!("failed to read {}", crate_list.to_string_lossy()))?;
+
+    let url = if url.starts_with("sparse+") {
+        String::from(url)
+    } else {
+        format!("sparse+{}", url)
+    };
+    let index = SparseIndex::at_path(PathBuf::new(), url);
+    let client = reqwest::blocking::Client::new();
+
+    let mut crates = HashMap::new();
+    for name in names.lines().map(str::trim).filter(|x| !x.is_empty()) {
+        let url = index
+            .crate_url(name)
+            .ok_or_else(|| anyhow!("invalid crate name: {}", name))?;
+        let res = client.get(&url).send()?;
+        if res.status() == StatusCode::NOT_FOUND {
+            continue;
+        }
+        let buf = res.error_for_status()?.bytes()?;
+        let c = Crate::from_slice(&buf)?;
+        crates.insert(String::from(c.name()), c);
+    }
+    Ok(crates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use serde_json::{json, Value};
+
+    /// Version of index, which depends on any version of `deps` on the target ( `null` for all )
+    fn index_version(name: &str, vers: &str, deps: &[(&str, Value)], yanked: bool) -> Value {
+        let deps: Vec<_> = deps
+            .iter()
+            .map(|(x, target)| {
+                json!({
+                    "name": x, "req": "*", "features": [], "optional": false,
+                    "default_features": true, "target": target, "kind": "normal"
+                })
+            })
+            .collect();
+        json!({
+            "name": name, "vers": vers, "deps": deps, "features": {},
+            "cksum": "0".repeat(64), "yanked": yanked
         })
-        .last();
-
-    let mut ret = Vec::new();
-    if let Some(krate) = krate {
-        let enabled_deps = gather_enabled_dependencies(
-            krate.features(),
-            enabled_features,
-            100,
-            &mut HashSet::new(),
-        );
+    }
 
-        for dep in krate.dependencies() {
-            if dep.is_optional() {
-                if enabled_deps.iter().any(|x| x == dep.crate_name()) {
-                    ret.push(dep.clone());
-                }
-            } else {
-                ret.push(dep.clone());
-            }
+    /// Line of index file, which depends on any version of `deps`
+    fn index_line(name: &str, vers: &str, deps: &[&str]) -> String {
+        let deps: Vec<_> = deps.iter().map(|x| (*x, Value::Null)).collect();
+        index_version(name, vers, &deps, false).to_string()
+    }
+
+    /// Crates of index versions, which are grouped by name
+    fn crates_of(versions: &[Value]) -> HashMap<String, Crate> {
+        let mut lines: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for x in versions {
+            let name = String::from(x["name"].as_str().unwrap());
+            lines.entry(name).or_default().push(x.to_string());
         }
+        lines
+            .into_iter()
+            .map(|(name, x)| (name, Crate::from_slice(x.join("\n").as_bytes()).unwrap()))
+            .collect()
     }
 
-    ret
-}
+    /// Crates of index with the names of their dependencies
+    type Index<'a> = [(&'a str, &'a [&'a str])];
 
-fn gather_enabled_dependencies(
-    features: &HashMap<String, Vec<String>>,
-    enabled_features: &[String],
-    max_depth: usize,
-    checked: &mut HashSet<String>,
-) -> Vec<String> {
-    let mut ret = Vec::new();
-    for enabled in enabled_features {
-        // break feature loop
-        if checked.contains(enabled) {
-            continue;
+    /// Write index files of crates with the layout of crates.io-index
+    fn write_index(dir: &Path, crates: &Index) {
+        for (name, deps) in crates {
+            let path = match name.len() {
+                1 => dir.join("1"),
+                2 => dir.join("2"),
+                3 => dir.join("3").join(&name[..1]),
+                _ => dir.join(&name[..2]).join(&name[2..4]),
+            };
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join(name), index_line(name, "1.0.0", deps) + "\n").unwrap();
         }
-        checked.insert(enabled.clone());
+    }
 
-        if let Some(expanded) = features.get(enabled) {
-            for e in expanded {
-                let mut children = if max_depth == 0 {
-                    Vec::new()
-                } else {
-                    gather_enabled_dependencies(features, &vec![e.clone()], max_depth - 1, checked)
-                };
-                if children.is_empty() {
-                    ret.push(e.clone());
-                } else {
-                    ret.append(&mut children);
-                }
+    /// Commit index files of each snapshot into a git repository at the time
+    fn git_index(dir: &Path, snapshots: &[(i64, &Index)]) {
+        let repo = Repository::init(dir).unwrap();
+        let mut parent = None;
+        for (time, crates) in snapshots {
+            write_index(dir, crates);
+            let mut index = repo.index().unwrap();
+            index
+                .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+                .unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let sig = git2::Signature::new("test", "test@example.com", &git2::Time::new(*time, 0))
+                .unwrap();
+            let parents: Vec<_> = parent.iter().collect();
+            let id = repo
+                .commit(Some("HEAD"), &sig, &sig, "update", &tree, &parents)
+                .unwrap();
+            parent = Some(repo.find_commit(id).unwrap());
+        }
+    }
+
+    // 2024-01-01 and 2024-01-02
+    const DAY1: i64 = 1704067200;
+    const DAY2: i64 = 1704153600;
+
+    #[test]
+    fn update_snapshot_of_targets() {
+        // a -(windows)-> b -> c, and d -> b
+        let crates = crates_of(&[
+            index_version("a", "1.0.0", &[("b", json!("cfg(windows)"))], false),
+            index_version("b", "1.0.0", &[("c", Value::Null)], false),
+            index_version("c", "1.0.0", &[], false),
+            index_version("d", "1.0.0", &[("b", Value::Null)], false),
+        ]);
+        let triples = ["x86_64-unknown-linux-gnu", "x86_64-pc-windows-msvc"];
+        let targets: Vec<Target> = triples.iter().map(|x| Target::new(x).unwrap()).collect();
+
+        let mut db = Db::new();
+        let time = Utc.timestamp_opt(DAY1, 0).unwrap();
+        db.update_snapshot(time, &crates, &UpdateOption::new(), &targets);
+
+        let b = &db.map["b"][0];
+        assert_eq!(b.direct_dependents, 2);
+        assert_eq!(b.target_direct_dependents, [1, 2]);
+        let c = &db.map["c"][0];
+        assert_eq!(c.transitive_dependents, 3);
+        assert_eq!(c.target_transitive_dependents, [2, 3]);
+    }
+
+    #[test]
+    fn update_snapshot_of_yanked_versions() {
+        let crates = crates_of(&[
+            index_version("a", "1.0.0", &[("foo", Value::Null)], false),
+            index_version("foo", "1.0.0", &[], false),
+            index_version("foo", "1.1.0", &[], true),
+            index_version("foo", "1.2.0", &[], false),
+        ]);
+
+        let mut db = Db::new();
+        let time = Utc.timestamp_opt(DAY1, 0).unwrap();
+        db.update_snapshot(time, &crates, &UpdateOption::new(), &[]);
+        let foo = &db.map["foo"][0];
+        assert_eq!((foo.yanked_versions, foo.total_versions), (1, 3));
+        assert_eq!(foo.yanked_share(), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn edges_since_first_update_after_migration() {
+        // entry without dependents like db of the older versions
+        let mut db = Db::new();
+        let day1 = Utc.timestamp_opt(DAY1, 0).unwrap();
+        let mut entry = Entry::new(day1, 2, 0);
+        entry.direct_dependents = 1;
+        db.map.insert(String::from("a"), vec![entry]);
+        db.update = day1;
+        assert!(db.check_edges(day1.date_naive()).is_err());
+
+        let crates = crates_of(&[
+            index_version("a", "1.0.0", &[], false),
+            index_version("b", "1.0.0", &[("a", Value::Null)], false),
+        ]);
+        let day2 = Utc.timestamp_opt(DAY2, 0).unwrap();
+        db.update_snapshot(day2, &crates, &UpdateOption::new(), &[]);
+        db.update = day2;
+        assert_eq!(db.edges_since, Some(day2));
+
+        // b is added by the first update, but it may have depended on a at DAY1
+        assert!(db.check_edges(day1.date_naive()).is_err());
+        assert!(db.diff("a", day1.date_naive(), day2.date_naive()).is_err());
+        let (gained, lost) = db.diff("a", day2.date_naive(), day2.date_naive()).unwrap();
+        assert!(gained.is_empty() && lost.is_empty());
+        assert_eq!(
+            db.dependents("a", day2.date_naive()),
+            BTreeSet::from([String::from("b")])
+        );
+
+        let dir = tempdir().unwrap();
+        db.save(dir.path()).unwrap();
+        assert_eq!(Db::load(dir.path(), None).unwrap().edges_since, Some(day2));
+    }
+
+    /// Entry at `day` days after DAY1 with the dependents added and removed since the last entry
+    fn delta(day: i64, added: &[&str], removed: &[&str]) -> Entry {
+        let mut entry = Entry::new(Utc.timestamp_opt(DAY1 + day * 86400, 0).unwrap(), 10, 0);
+        entry.added_dependents = added.iter().map(|x| String::from(*x)).collect();
+        entry.removed_dependents = removed.iter().map(|x| String::from(*x)).collect();
+        entry
+    }
+
+    /// Db of the entries, whose dependents are known since DAY1
+    fn delta_db(map: Vec<(&str, Vec<Entry>)>) -> Db {
+        let mut db = Db::new();
+        for (name, entries) in map {
+            db.map.insert(String::from(name), entries);
+        }
+        db.edges_since = Some(Utc.timestamp_opt(DAY1, 0).unwrap());
+        db
+    }
+
+    #[test]
+    fn diff_of_switched_dependents() {
+        // x and y switch to once_cell, z adds p and q, and w adds nothing
+        let db = delta_db(vec![
+            (
+                "lazy_static",
+                vec![
+                    delta(0, &["w", "x", "y", "z"], &[]),
+                    delta(1, &["v"], &["w", "x", "y", "z"]),
+                ],
+            ),
+            ("once_cell", vec![delta(1, &["x", "y"], &[])]),
+            ("aaa", vec![delta(0, &["u"], &[]), delta(1, &["y"], &[])]),
+            ("p", vec![delta(1, &["z"], &[])]),
+            ("q", vec![delta(1, &["z"], &[])]),
+        ]);
+        let day = |x: i64| Utc.timestamp_opt(DAY1 + x * 86400, 0).unwrap().date_naive();
+
+        let (gained, lost) = db.diff("lazy_static", day(0), day(1)).unwrap();
+        assert_eq!(gained, ["v"]);
+        let switched = |x: &str| Some(String::from(x));
+        assert_eq!(
+            lost,
+            [
+                (String::from("w"), None),
+                // once_cell is added by more lost dependents than aaa
+                (String::from("x"), switched("once_cell")),
+                (String::from("y"), switched("once_cell")),
+                // p and q are added by the same number of them, so the first name is taken
+                (String::from("z"), switched("p")),
+            ]
+        );
+
+        // nothing is changed on the same date
+        let (gained, lost) = db.diff("lazy_static", day(1), day(1)).unwrap();
+        assert!(gained.is_empty() && lost.is_empty());
+    }
+
+    #[test]
+    fn migration_at_the_same_snapshot() {
+        let direct = |mut entry: Entry, n| {
+            entry.direct_dependents = n;
+            entry
+        };
+        // x switches from a to b and z from b to a at day 1, but y adds b at the next snapshot
+        let db = delta_db(vec![
+            (
+                "a",
+                vec![
+                    direct(delta(0, &["x", "y"], &[]), 2),
+                    direct(delta(1, &["z"], &["x", "y"]), 1),
+                ],
+            ),
+            (
+                "b",
+                vec![
+                    direct(delta(0, &["z"], &[]), 1),
+                    direct(delta(1, &["x"], &["z"]), 1),
+                    direct(delta(2, &["y"], &[]), 2),
+                ],
+            ),
+        ]);
+
+        let rows: Vec<_> = db
+            .migration("a", "b")
+            .into_iter()
+            .map(|x| {
+                let day = (x.time.timestamp() - DAY1) / 86400;
+                (day, x.forward, x.backward, x.dependents)
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [(0, 0, 0, (2, 1)), (1, 1, 1, (1, 1)), (2, 0, 0, (1, 2))]
+        );
+    }
+
+    #[test]
+    fn migration_at_different_snapshots() {
+        // y drops a at day 1 and adds b at day 2, which isn't a switch
+        let db = delta_db(vec![
+            ("a", vec![delta(0, &["y"], &[]), delta(1, &[], &["y"])]),
+            ("b", vec![delta(0, &["w"], &[]), delta(2, &["y"], &[])]),
+        ]);
+        let migration = db.migration("a", "b");
+        assert_eq!(migration.len(), 3);
+        assert!(migration.iter().all(|x| x.forward == 0 && x.backward == 0));
+    }
+
+    #[test]
+    fn update_from_file_git_index() {
+        let index = tempdir().unwrap();
+        git_index(
+            index.path(),
+            &[
+                (DAY1, &[("a", &[]), ("b", &["a"])]),
+                (DAY2, &[("ccc", &["a", "b"])]),
+            ],
+        );
+
+        let mut db = Db::new();
+        let url = format!("file://{}", index.path().to_string_lossy());
+        db.update(&UpdateOption::new().index_url(Some(url)))
+            .unwrap();
+
+        let direct: Vec<_> = db.map["a"].iter().map(|x| x.direct_dependents).collect();
+        assert_eq!(direct, [1, 2]);
+        assert_eq!(db.map["a"][1].added_dependents, ["ccc"]);
+        assert_eq!(db.update.timestamp(), DAY2);
+    }
+
+    #[test]
+    fn update_is_independent_of_jobs() {
+        let index = tempdir().unwrap();
+        git_index(
+            index.path(),
+            &[
+                (DAY1, &[("a", &[]), ("b", &["a"]), ("ccc", &["b"])]),
+                (DAY2, &[("dddd", &["a", "ccc"]), ("eeee", &["dddd", "b"])]),
+            ],
+        );
+        let url = format!("file://{}", index.path().to_string_lossy());
+
+        let mut saved = Vec::new();
+        for jobs in [1, 4] {
+            let mut db = Db::new();
+            let option = UpdateOption::new()
+                .index_url(Some(url.clone()))
+                .jobs(Some(jobs));
+            db.update(&option).unwrap();
+
+            let dir = tempdir().unwrap();
+            db.save(dir.path()).unwrap();
+            let mut files = Vec::new();
+            for name in ["db.json", "db0"] {
+                files.push(fs::read(dir.path().join(name)).unwrap());
             }
+            saved.push(files);
         }
+        assert_eq!(saved[0], saved[1]);
+    }
+
+    #[test]
+    fn update_resumes_from_checkpoint() {
+        let index = tempdir().unwrap();
+        git_index(
+            index.path(),
+            &[
+                (DAY1, &[("a", &[]), ("b", &["a"])]),
+                (DAY2, &[("ccc", &["a", "b"])]),
+            ],
+        );
+        let url = format!("file://{}", index.path().to_string_lossy());
+
+        // the checkpoint is saved after DAY1, and the update is interrupted before the last save
+        let dir = tempdir().unwrap();
+        let option = UpdateOption::new()
+            .index_url(Some(url))
+            .checkpoint(dir.path().to_path_buf(), 1);
+        let mut full = Db::new();
+        full.update(&option).unwrap();
+
+        let mut db = Db::load(dir.path(), None).unwrap();
+        assert_eq!(db.update.timestamp(), DAY1);
+        assert_eq!(db.map["a"].len(), 1);
+
+        db.update(&option).unwrap();
+        assert_eq!(db.update.timestamp(), DAY2);
+        assert_eq!(db.map, full.map);
+    }
+
+    #[test]
+    fn update_refuses_other_dev_transitive() {
+        let index = tempdir().unwrap();
+        git_index(index.path(), &[(DAY1, &[("a", &[]), ("b", &["a"])])]);
+        let url = format!("file://{}", index.path().to_string_lossy());
+
+        let dir = tempdir().unwrap();
+        let mut db = Db::new();
+        let option = UpdateOption::new()
+            .index_url(Some(url.clone()))
+            .dev_transitive(true);
+        db.update(&option).unwrap();
+        db.save(dir.path()).unwrap();
+
+        let mut db = Db::load(dir.path(), None).unwrap();
+        assert!(db.dev_transitive);
+        assert!(db
+            .update(&UpdateOption::new().index_url(Some(url)))
+            .is_err());
+    }
+
+    #[test]
+    fn update_refuses_other_include_yanked() {
+        let index = tempdir().unwrap();
+        git_index(index.path(), &[(DAY1, &[("a", &[]), ("b", &["a"])])]);
+        let url = format!("file://{}", index.path().to_string_lossy());
+
+        let dir = tempdir().unwrap();
+        let mut db = Db::new();
+        db.update(&UpdateOption::new().index_url(Some(url.clone())))
+            .unwrap();
+        db.save(dir.path()).unwrap();
+
+        let mut db = Db::load(dir.path(), None).unwrap();
+        assert!(!db.include_yanked);
+        let option = UpdateOption::new()
+            .index_url(Some(url))
+            .include_yanked(true);
+        assert!(db.update(&option).is_err());
+    }
+
+    /// Db which has `n` entries of each crate at `day` days after DAY1
+    fn synthetic_db(crates: &[(&str, i64, usize)]) -> Db {
+        let mut db = Db::new();
+        for &(name, day, n) in crates {
+            let time = Utc.timestamp_opt(DAY1 + day * 86400, 0).unwrap();
+            db.map
+                .entry(String::from(name))
+                .or_default()
+                .extend(vec![Entry::new(time, 3, 0); n]);
+            db.update = db.update.max(time);
+        }
+        db
+    }
+
+    fn read_header(dir: &Path) -> DbHeader {
+        serde_json::from_str(&fs::read_to_string(dir.join("db.json")).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn save_appends_to_recent_chunks() {
+        // crate0 and crate1 are in db0, crate2 is in db1, and crate3 is in db2 and db3
+        let n = CHUNK_ENTRIES / 2;
+        let mut db = synthetic_db(&[("crate0", 0, n), ("crate1", 0, n), ("crate2", 0, n)]);
+        db.base = db.update;
+        db.map.extend(synthetic_db(&[("crate3", 1, n * 3)]).map);
+        db.update += Duration::days(1);
+
+        let old = tempdir().unwrap();
+        db.save(old.path()).unwrap();
+        let old = read_header(old.path());
+        assert_eq!(old.index, ["crate0", "crate2"]);
+        assert_eq!(old.hash.len(), 4);
+
+        // the next day is appended to the last recent chunk
+        db.update += Duration::days(1);
+        let entry = Entry::new(db.update, 3, 0);
+        db.map.get_mut("crate0").unwrap().push(entry);
+        let dir = tempdir().unwrap();
+        db.save(dir.path()).unwrap();
+        let new = read_header(dir.path());
+        assert_eq!(new.hash[..3], old.hash[..3]);
+        assert_ne!(new.hash[3], old.hash[3]);
+
+        let loaded = Db::load(dir.path(), Some(&[String::from("crate0")])).unwrap();
+        assert_eq!(loaded.map.len(), 1);
+        assert_eq!(loaded.map["crate0"].len(), n + 1);
+        assert_eq!(loaded.map["crate0"][n].time, db.update);
+    }
+
+    #[test]
+    fn fetch_crates_removes_obsolete_chunks() {
+        let n = CHUNK_ENTRIES;
+        let mut db = synthetic_db(&[("crate0", 0, n), ("crate1", 0, n), ("crate2", 1, 1)]);
+        db.base = Utc.timestamp_opt(DAY1, 0).unwrap();
+        let source = tempdir().unwrap();
+        db.save(source.path()).unwrap();
+        let source = source.path().to_string_lossy();
+
+        // db1 of crate1 is obsolete, and it is not fetched with crate0
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("db1"), b"obsolete").unwrap();
+        let crates = [String::from("crate0")];
+        Db::fetch(dir.path(), &source, Some(&crates)).unwrap();
+        assert!(dir.path().join("db0").exists());
+        assert!(!dir.path().join("db1").exists());
+        assert!(dir.path().join("db2").exists());
+
+        let loaded = Db::load(dir.path(), Some(&crates)).unwrap();
+        assert_eq!(loaded.map.len(), 1);
+        assert_eq!(loaded.map["crate0"].len(), n);
+        let err = Db::load(dir.path(), None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::NotFound(x)) if x == "db1"
+        ));
+
+        Db::fetch(dir.path(), &source, None).unwrap();
+        assert_eq!(Db::load(dir.path(), None).unwrap().map, db.map);
+    }
+
+    #[test]
+    fn repair_keeps_chunks() {
+        let dir = tempdir().unwrap();
+        let source = tempdir().unwrap();
+        let n = CHUNK_ENTRIES;
+        let mut db = synthetic_db(&[("crate0", 0, n), ("crate1", 0, n), ("crate2", 0, n)]);
+        db.base = db.update;
+        db.save(dir.path()).unwrap();
+        db.save(source.path()).unwrap();
+        let source = source.path().to_string_lossy();
+
+        // corrupted chunk is fetched from the source
+        fs::write(dir.path().join("db1"), b"broken").unwrap();
+        Db::repair(dir.path(), &source, None).unwrap();
+        assert_eq!(Db::load(dir.path(), None).unwrap().map.len(), 3);
+
+        // chunk which can't be fetched is reported, and the others are kept
+        fs::write(dir.path().join("db1"), b"broken").unwrap();
+        let err = Db::repair(dir.path(), "/nonexistent", None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::Unrepairable { chunks, .. }) if chunks == &["db1"]
+        ));
+        assert_eq!(fs::read(dir.path().join("db1")).unwrap(), b"broken");
+        let db = Db::load(dir.path(), Some(&[String::from("crate0")])).unwrap();
+        assert_eq!(db.map["crate0"].len(), CHUNK_ENTRIES);
+    }
+
+    #[test]
+    fn update_from_empty_sparse_dir() {
+        let index = tempdir().unwrap();
+        git_index(index.path(), &[(DAY1, &[("a", &[]), ("b", &["a"])])]);
+        let mut db = Db::new();
+        let url = format!("file://{}", index.path().to_string_lossy());
+        db.update(&UpdateOption::new().index_url(Some(url)))
+            .unwrap();
+
+        // neither entries nor the update time are changed
+        let sparse = tempdir().unwrap();
+        let option = UpdateOption::new().sparse(Some(sparse.path().to_string_lossy().into()));
+        assert!(db.update(&option).is_err());
+        assert_eq!(db.map["a"].len(), 1);
+        assert_eq!(db.update.timestamp(), DAY1);
+
+        fs::write(sparse.path().join("a"), "broken").unwrap();
+        assert!(db.update(&option).is_err());
+        assert_eq!(db.update.timestamp(), DAY1);
+    }
+
+    #[test]
+    fn update_refuses_crate_list_for_whole_index() {
+        let index = tempdir().unwrap();
+        git_index(index.path(), &[(DAY1, &[("a", &[]), ("b", &["a"])])]);
+        let mut db = Db::new();
+        let url = format!("file://{}", index.path().to_string_lossy());
+        db.update(&UpdateOption::new().index_url(Some(url)))
+            .unwrap();
+
+        // refused before fetching the crates
+        let list = index.path().join("crates.txt");
+        fs::write(&list, "a\n").unwrap();
+        let option = UpdateOption::new()
+            .sparse(Some(String::from("http://127.0.0.1:1/")))
+            .crate_list(Some(list));
+        let err = db.update(&option).unwrap_err();
+        assert!(err.to_string().contains("crate list"));
+        assert_eq!(db.map["a"].len(), 1);
+    }
+
+    #[test]
+    fn update_from_sparse_dir() {
+        let index = tempdir().unwrap();
+        write_index(
+            index.path(),
+            &[("a", &[]), ("b", &["a"]), ("ccc", &["a", "b"])],
+        );
+        fs::write(index.path().join("config.json"), r#"{"dl":"http://x"}"#).unwrap();
+
+        let mut db = Db::new();
+        let option = UpdateOption::new().sparse(Some(index.path().to_string_lossy().into()));
+        db.update(&option).unwrap();
+
+        assert_eq!(db.map["a"].len(), 1);
+        assert_eq!(db.map["a"][0].direct_dependents, 2);
+        assert_eq!(db.map["a"][0].transitive_dependents, 2);
+        assert_eq!(db.map["b"][0].direct_dependents, 1);
+        assert!(!db.map.contains_key("ccc"));
+        assert_eq!(
+            db.dependents("a", db.update.date_naive()),
+            BTreeSet::from([String::from("b"), String::from("ccc")])
+        );
     }
-    ret
 }
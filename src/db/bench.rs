@@ -0,0 +1,96 @@
+//! Benchmark of fetch and load on a synthetic db
+//!
+//! Plotting a few crates reads only the chunks containing them, so it is compared with reading
+//! all crates, which is required by `--top-*` options and the older versions.
+//! It is ignored by default, and run by `cargo test --release -- --ignored --nocapture bench`.
+
+use super::{Db, DbHeader, Entry};
+use chrono::{Duration, TimeZone, Utc};
+use std::fs;
+use std::time::Instant;
+use tempfile::tempdir;
+
+/// Number of crates plotted like `cargo trend tokio smol async-std`
+const PLOTTED: usize = 3;
+
+fn run(crates: usize, entries: usize, recent: usize) {
+    let tmp = tempdir().unwrap();
+    let source = tmp.path().join("source");
+
+    // each crate has an entry every day, and gains a dependent in each entry
+    let start = Utc.timestamp_opt(1500000000, 0).unwrap();
+    let names: Vec<String> = (0..crates).map(|i| format!("crate{:06}", i)).collect();
+    let mut db = Db::new();
+    for (i, name) in names.iter().enumerate() {
+        let history = (0..entries)
+            .map(|j| {
+                let dependents = (i % 1000 + j) as u64;
+                let mut entry = Entry::new(start + Duration::days(j as i64), crates as u64, 0);
+                entry.direct_dependents = dependents;
+                entry.transitive_dependents = dependents * 4;
+                entry.normal_dependents = dependents;
+                entry.total_versions = j as u64 + 1;
+                entry.compat_dependents = vec![(String::from("1"), dependents)];
+                entry.latest_dependents = dependents;
+                entry.added_dependents = vec![names[(i + j + 1) % crates].clone()];
+                entry
+            })
+            .collect();
+        db.map.insert(name.clone(), history);
+    }
+    db.update = start + Duration::days(entries as i64 - 1);
+
+    // the last days are in recent chunks
+    db.base = db.update - Duration::days(recent as i64);
+
+    let begin = Instant::now();
+    db.save(&source).unwrap();
+    let header: DbHeader =
+        serde_json::from_str(&fs::read_to_string(source.join("db.json")).unwrap()).unwrap();
+    println!(
+        "Bench DB: {} crates, {} entries, {} base chunks, {} recent chunks ( saved in {:.3}s )",
+        crates,
+        crates * entries,
+        header.index.len(),
+        header.hash.len() - header.index.len(),
+        begin.elapsed().as_secs_f64()
+    );
+    drop(db);
+
+    // plotted crates are spread over the db, so they are in different chunks if possible
+    let plotted: Vec<String> = (0..PLOTTED)
+        .map(|i| names[i * crates / PLOTTED].clone())
+        .collect();
+    let source = source.to_string_lossy();
+    let cases = [
+        (String::from("all crates"), None),
+        (format!("{} crates", PLOTTED), Some(plotted.as_slice())),
+    ];
+
+    for (i, (label, crates)) in cases.iter().enumerate() {
+        let dir = tmp.path().join(format!("cache{}", i));
+
+        let begin = Instant::now();
+        Db::fetch(&dir, &source, *crates).unwrap();
+        let fetch = begin.elapsed();
+
+        let begin = Instant::now();
+        let db = Db::load(&dir, *crates).unwrap();
+        let load = begin.elapsed();
+
+        println!(
+            "  {:<10}: fetch {:.3}s ( {} chunks ), load {:.3}s ( {} crates )",
+            label,
+            fetch.as_secs_f64(),
+            header.chunks(*crates).len(),
+            load.as_secs_f64(),
+            db.map.len()
+        );
+    }
+}
+
+#[test]
+#[ignore]
+fn bench_fetch_and_load() {
+    run(100000, 20, 2);
+}
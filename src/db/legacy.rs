@@ -0,0 +1,203 @@
+//! Readers of older db layouts
+//!
+//! db_v1 and db_v2 are gzipped JSON ( `db.gz` ), and db_v3 is `db.json` with dlhn chunks of
+//! `(name, entry)` rows sorted by time.
+//! dlhn chunks have no field name, so the `Entry` layout of db_v3 is kept as `EntryV3`.
+//! Metrics which didn't exist in the version are zero in the converted entries.
+
+use super::{read_chunk, Db, DbError, Entry};
+use anyhow::{Context, Error};
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+#[derive(Deserialize)]
+struct DbV2 {
+    update: DateTime<Utc>,
+    map: HashMap<String, Vec<EntryV2>>,
+}
+
+/// db_v1 has `dependents` only
+#[derive(Deserialize)]
+struct EntryV2 {
+    #[serde(with = "ts_seconds")]
+    time: DateTime<Utc>,
+    #[serde(alias = "dependents")]
+    direct_dependents: u64,
+    #[serde(default)]
+    transitive_dependents: u64,
+    #[serde(default)]
+    total_crates: u64,
+}
+
+#[derive(Deserialize)]
+struct Chunk<T> {
+    data: Vec<(String, T)>,
+}
+
+#[derive(Deserialize)]
+struct EntryV3 {
+    #[serde(with = "ts_seconds")]
+    time: DateTime<Utc>,
+    direct_dependents: u64,
+    transitive_dependents: u64,
+    total_crates: u64,
+}
+
+impl From<EntryV2> for Entry {
+    fn from(x: EntryV2) -> Self {
+        Entry {
+            direct_dependents: x.direct_dependents,
+            transitive_dependents: x.transitive_dependents,
+            ..Entry::new(x.time, x.total_crates, 0)
+        }
+    }
+}
+
+impl From<EntryV3> for Entry {
+    fn from(x: EntryV3) -> Self {
+        Entry {
+            direct_dependents: x.direct_dependents,
+            transitive_dependents: x.transitive_dependents,
+            ..Entry::new(x.time, x.total_crates, 0)
+        }
+    }
+}
+
+/// Load `db.gz` of db_v1 or db_v2
+pub fn load_gz(dir: &Path) -> Result<Db, Error> {
+    let path = dir.join("db.gz");
+    let file =
+        File::open(&path).with_context(|| format!("failed to read {}", path.to_string_lossy()))?;
+    let old: DbV2 = serde_json::from_reader(GzDecoder::new(file))
+        .with_context(|| format!("failed to parse {}", path.to_string_lossy()))?;
+
+    let mut db = Db::new();
+    db.update = old.update;
+    db.dev_transitive = true;
+    db.include_yanked = true;
+    for (name, entries) in old.map {
+        db.map
+            .insert(name, entries.into_iter().map(Entry::from).collect());
+    }
+    Ok(db)
+}
+
+/// Read a chunk of db_v3, and convert it to the current `Entry`
+pub fn read_chunk_of(
+    dir: &Path,
+    i: usize,
+    hash: &str,
+    version: u32,
+) -> Result<Vec<(String, Entry)>, Error> {
+    match version {
+        3 => convert::<EntryV3>(dir, i, hash),
+        _ => Err(DbError::Version(version).into()),
+    }
+}
+
+fn convert<T: DeserializeOwned + Into<Entry>>(
+    dir: &Path,
+    i: usize,
+    hash: &str,
+) -> Result<Vec<(String, Entry)>, Error> {
+    let chunk: Chunk<T> = read_chunk(dir, i, hash)?;
+    Ok(chunk.data.into_iter().map(|(n, e)| (n, e.into())).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{hash_file, write_file, DbHeader, DB_VERSION};
+    use super::*;
+    use chrono::TimeZone;
+    use dlhn::Serializer;
+    use serde::Serialize;
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// `EntryV3` with the same layout to write fixture
+    #[derive(Serialize)]
+    struct FixtureV3 {
+        time: i64,
+        direct_dependents: u64,
+        transitive_dependents: u64,
+        total_crates: u64,
+    }
+
+    #[derive(Serialize)]
+    struct FixtureChunk {
+        data: Vec<(String, FixtureV3)>,
+    }
+
+    /// Migrate a copy of db, and check it has the same entries
+    fn check_migrate(src: &Db, dir: &Path) {
+        Db::migrate(dir).unwrap();
+        let header: DbHeader =
+            serde_json::from_str(&fs::read_to_string(dir.join("db.json")).unwrap()).unwrap();
+        assert_eq!(header.version, DB_VERSION);
+        assert!(!dir.join("db.gz").exists());
+
+        let db = Db::load(dir, None).unwrap();
+        assert_eq!(db.update, src.update);
+        assert_eq!(db.map, src.map);
+        assert!(db.dev_transitive && db.include_yanked);
+        assert!(db.edges_since.is_none());
+    }
+
+    #[test]
+    fn migrate_db_gz() {
+        // ./db/db.gz is db_v1, which has `dependents` only
+        let dir = tempdir().unwrap();
+        fs::copy("db/db.gz", dir.path().join("db.gz")).unwrap();
+
+        let db = Db::load(dir.path(), None).unwrap();
+        assert!(!db.map.is_empty());
+        assert!(db.map.values().flatten().any(|x| x.direct_dependents > 0));
+        check_migrate(&db, dir.path());
+    }
+
+    #[test]
+    fn migrate_db_v3() {
+        let dir = tempdir().unwrap();
+
+        // 2020-01-01
+        let time = 1577836800;
+        let row = |name: &str, day: i64, direct, transitive, total| {
+            let entry = FixtureV3 {
+                time: time + day * 86400,
+                direct_dependents: direct,
+                transitive_dependents: transitive,
+                total_crates: total,
+            };
+            (String::from(name), entry)
+        };
+        let chunk = FixtureChunk {
+            data: vec![
+                row("a", 0, 2, 3, 4),
+                row("b", 0, 1, 1, 4),
+                row("a", 1, 3, 4, 5),
+            ],
+        };
+        let mut buf = Vec::new();
+        chunk.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        write_file(&dir.path().join("db0"), &buf).unwrap();
+        let hash = hash_file(&dir.path().join("db0")).unwrap();
+        let header = format!(r#"{{"update":"2020-01-02T00:00:00Z","hash":["{}"]}}"#, hash);
+        fs::write(dir.path().join("db.json"), header).unwrap();
+
+        let db = Db::load(dir.path(), None).unwrap();
+        let a: Vec<_> = db.map["a"]
+            .iter()
+            .map(|x| (x.direct_dependents, x.transitive_dependents, x.total_crates))
+            .collect();
+        assert_eq!(a, [(2, 3, 4), (3, 4, 5)]);
+        assert_eq!(db.map["a"][0].time, Utc.timestamp_opt(time, 0).unwrap());
+        assert_eq!(db.map["b"][0].normal_dependents, 0);
+        check_migrate(&db, dir.path());
+    }
+}
@@ -0,0 +1,1103 @@
+use anyhow::{anyhow, Error};
+use cfg_expr::targets::{get_builtin_target_by_triple, TargetInfo};
+use cfg_expr::{Expression, Predicate};
+use crates_index::{Crate, Dependency, DependencyKind};
+use rayon::prelude::*;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// Dependency graph of a snapshot of index
+pub struct Graph<'a> {
+    crates: &'a [&'a Crate],
+    id_of: &'a HashMap<&'a str, u32>,
+    versions: Vec<Vec<Option<Version>>>,
+    latest: Vec<Option<usize>>,
+    dev_transitive: bool,
+    target: Option<&'a Target>,
+    include_yanked: bool,
+    features: FeaturePolicy,
+}
+
+/// Features enabled on crates used as root
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FeaturePolicy {
+    /// `default` feature only
+    #[default]
+    Default,
+    /// All features and optional dependencies
+    All,
+    /// No feature
+    NoDefault,
+}
+
+impl FromStr for FeaturePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(FeaturePolicy::Default),
+            "all" => Ok(FeaturePolicy::All),
+            "no-default" => Ok(FeaturePolicy::NoDefault),
+            _ => Err(anyhow!("unknown feature policy {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FeaturePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeaturePolicy::Default => write!(f, "default"),
+            FeaturePolicy::All => write!(f, "all"),
+            FeaturePolicy::NoDefault => write!(f, "no-default"),
+        }
+    }
+}
+
+/// Target platform to evaluate target-specific dependencies
+pub struct Target {
+    triple: String,
+    info: &'static TargetInfo,
+}
+
+impl Target {
+    pub fn new(triple: &str) -> Result<Self, Error> {
+        let info = get_builtin_target_by_triple(triple)
+            .ok_or_else(|| anyhow!("unknown target triple {}", triple))?;
+        Ok(Target {
+            triple: String::from(triple),
+            info,
+        })
+    }
+
+    /// Whether the `target` field of dependency, which is `cfg()` expression or triple, matches
+    fn matches(&self, target: &str) -> bool {
+        if target.starts_with("cfg(") {
+            // cargo evaluates the expression without `test`, `debug_assertions` and features
+            match Expression::parse(target) {
+                Ok(expr) => expr.eval(|pred| match pred {
+                    Predicate::Target(x) => x.matches(self.info),
+                    _ => false,
+                }),
+                Err(_) => false,
+            }
+        } else {
+            target == self.triple
+        }
+    }
+}
+
+/// A crate resolved by version requirement and enabled features
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Node {
+    id: u32,
+    version: Option<usize>,
+    features: Vec<String>,
+    // dev-dependencies are followed from roots only
+    dev: bool,
+}
+
+impl<'a> Graph<'a> {
+    pub fn new(crates: &'a [&'a Crate], id_of: &'a HashMap<&'a str, u32>) -> Self {
+        let versions: Vec<Vec<Option<Version>>> = crates
+            .par_iter()
+            .map(|c| {
+                c.versions()
+                    .iter()
+                    .map(|x| Version::parse(x.version()).ok())
+                    .collect()
+            })
+            .collect();
+
+        // the newest release is not changed by options unlike root
+        let latest = crates
+            .par_iter()
+            .zip(&versions)
+            .map(|(c, versions)| {
+                versions
+                    .iter()
+                    .zip(c.versions())
+                    .enumerate()
+                    .filter_map(|(i, (x, v))| match x {
+                        Some(x) if x.pre.is_empty() && !v.is_yanked() => Some((i, x)),
+                        _ => None,
+                    })
+                    .max_by(|a, b| a.1.cmp(b.1))
+                    .map(|(i, _)| i)
+            })
+            .collect();
+
+        Graph {
+            crates,
+            id_of,
+            versions,
+            latest,
+            dev_transitive: false,
+            target: None,
+            include_yanked: false,
+            features: FeaturePolicy::Default,
+        }
+    }
+
+    /// Features enabled on crates used as root
+    pub fn features(mut self, features: FeaturePolicy) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Resolve version requirements to yanked versions too ( yanked versions are skipped by default )
+    pub fn include_yanked(mut self, include_yanked: bool) -> Self {
+        self.include_yanked = include_yanked;
+        self
+    }
+
+    /// Ignore dependencies which are not used on the target ( all dependencies are used by default )
+    pub fn target(mut self, target: Option<&'a Target>) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Follow dev-dependencies of dependencies like the older versions
+    ///
+    /// Cargo never builds dev-dependencies of dependencies, so they are skipped by default.
+    pub fn dev_transitive(mut self, dev_transitive: bool) -> Self {
+        self.dev_transitive = dev_transitive;
+        self
+    }
+
+    /// Direct dependencies of a crate used as root
+    pub fn direct(&self, id: u32) -> Vec<Dependency> {
+        let node = self.root(id);
+        match node.version {
+            Some(v) => self
+                .dependencies(&self.crates[id as usize].versions()[v], &node.features)
+                .into_iter()
+                .map(|(dep, _)| dep)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Version which the requirement of dependency is resolved to
+    pub fn resolved(&self, dep: &Dependency) -> Option<&Version> {
+        let id = *self.id_of.get(dep.crate_name())?;
+        let requirement = VersionReq::parse(dep.requirement()).ok()?;
+        let v = self.resolve(id, &requirement)?;
+        self.versions[id as usize][v].as_ref()
+    }
+
+    /// Whether the requirement of dependency matches the newest non-yanked release
+    pub fn matches_latest(&self, dep: &Dependency) -> bool {
+        let latest = self
+            .id_of
+            .get(dep.crate_name())
+            .and_then(|&id| self.latest[id as usize].map(|v| (id, v)))
+            .and_then(|(id, v)| self.versions[id as usize][v].as_ref());
+        match (latest, VersionReq::parse(dep.requirement())) {
+            (Some(latest), Ok(requirement)) => requirement.matches(latest),
+            _ => false,
+        }
+    }
+
+    /// The number of crates which depend on each crate transitively
+    ///
+    /// The transitive closure is computed on the strongly connected components of the resolved
+    /// graph, so crates in a dependency cycle are counted the same way regardless of the order.
+    pub fn transitive_dependents(&self) -> Vec<u64> {
+        let n = self.crates.len();
+
+        let mut nodes = Vec::new();
+        let mut index = HashMap::new();
+        let mut adj: Vec<Vec<u32>> = Vec::new();
+        let mut intern = |node: Node, nodes: &mut Vec<Node>, adj: &mut Vec<Vec<u32>>| {
+            if let Some(&i) = index.get(&node) {
+                (i, false)
+            } else {
+                let i = nodes.len() as u32;
+                index.insert(node.clone(), i);
+                nodes.push(node);
+                adj.push(Vec::new());
+                (i, true)
+            }
+        };
+
+        let mut roots = Vec::new();
+        let mut frontier = Vec::new();
+        for id in 0..n as u32 {
+            let (i, new) = intern(self.root(id), &mut nodes, &mut adj);
+            roots.push(i);
+            if new {
+                frontier.push(i);
+            }
+        }
+
+        while !frontier.is_empty() {
+            let expanded: Vec<Vec<Node>> = frontier
+                .par_iter()
+                .map(|&i| self.edges(&nodes[i as usize]))
+                .collect();
+
+            let mut next = Vec::new();
+            for (&i, targets) in frontier.iter().zip(expanded) {
+                let mut out = Vec::with_capacity(targets.len());
+                for target in targets {
+                    let (j, new) = intern(target, &mut nodes, &mut adj);
+                    if new {
+                        next.push(j);
+                    }
+                    out.push(j);
+                }
+                adj[i as usize] = out;
+            }
+            frontier = next;
+        }
+
+        let crate_of: Vec<u32> = nodes.iter().map(|x| x.id).collect();
+        let (comp_of, reach) = closure(&adj, &crate_of);
+
+        roots
+            .par_iter()
+            .fold(
+                || vec![0; n],
+                |mut acc, &root| {
+                    for &x in &reach[comp_of[root as usize] as usize] {
+                        acc[x as usize] += 1;
+                    }
+                    acc
+                },
+            )
+            .reduce(
+                || vec![0; n],
+                |mut acc, x| {
+                    for (a, x) in acc.iter_mut().zip(x) {
+                        *a += x;
+                    }
+                    acc
+                },
+            )
+    }
+
+    fn root(&self, id: u32) -> Node {
+        let version = self.resolve(id, &VersionReq::STAR);
+        let features = match (self.features, version) {
+            (FeaturePolicy::Default, _) => vec![String::from("default")],
+            (FeaturePolicy::All, Some(v)) => {
+                let krate = &self.crates[id as usize].versions()[v];
+                let mut features: Vec<String> = krate.features().keys().cloned().collect();
+                for dep in krate.dependencies() {
+                    if dep.is_optional() {
+                        features.push(format!("dep:{}", dep.name()));
+                    }
+                }
+                features.sort();
+                features.dedup();
+                features
+            }
+            _ => Vec::new(),
+        };
+
+        Node {
+            id,
+            version,
+            features,
+            dev: true,
+        }
+    }
+
+    fn resolve(&self, id: u32, requirement: &VersionReq) -> Option<usize> {
+        // the highest version is picked regardless of the published order like cargo,
+        // and pre-releases are skipped unless the requirement has pre-release
+        let versions = self.crates[id as usize].versions();
+        self.versions[id as usize]
+            .iter()
+            .zip(versions)
+            .enumerate()
+            .filter_map(|(i, (x, v))| match x {
+                Some(version) if self.include_yanked || !v.is_yanked() => {
+                    requirement.matches(version).then_some((i, version))
+                }
+                _ => None,
+            })
+            .max_by(|a, b| a.1.cmp(b.1))
+            .map(|(i, _)| i)
+    }
+
+    fn dependencies(
+        &self,
+        version: &crates_index::Version,
+        features: &[String],
+    ) -> Vec<(Dependency, Vec<String>)> {
+        let mut ret = gather_dependencies(version, features);
+        if let Some(target) = self.target {
+            ret.retain(|(dep, _)| dep.target().is_none_or(|x| target.matches(x)));
+        }
+        ret
+    }
+
+    fn edges(&self, node: &Node) -> Vec<Node> {
+        let version = match node.version {
+            Some(v) => &self.crates[node.id as usize].versions()[v],
+            None => return Vec::new(),
+        };
+
+        let mut ret = Vec::new();
+        for (dep, mut features) in self.dependencies(version, &node.features) {
+            if dep.kind() == DependencyKind::Dev && !node.dev {
+                continue;
+            }
+
+            let id = match self.id_of.get(dep.crate_name()) {
+                Some(&i) => i,
+                None => continue,
+            };
+
+            if dep.has_default_features() {
+                features.push(String::from("default"));
+            }
+            features.sort();
+            features.dedup();
+
+            // unresolved dependency is counted, but not traced
+            let version = VersionReq::parse(dep.requirement())
+                .ok()
+                .and_then(|x| self.resolve(id, &x));
+
+            ret.push(Node {
+                id,
+                version,
+                features,
+                dev: self.dev_transitive,
+            });
+        }
+        ret
+    }
+}
+
+/// Crates reachable from each strongly connected component through one or more edges
+fn closure(adj: &[Vec<u32>], crate_of: &[u32]) -> (Vec<u32>, Vec<Vec<u32>>) {
+    let (comp_of, comps) = scc(adj);
+
+    // components are in reverse topological order, so successors always have lower level
+    let mut level = vec![0; comps.len()];
+    let mut levels: Vec<Vec<usize>> = Vec::new();
+    for (c, members) in comps.iter().enumerate() {
+        for &u in members {
+            for &v in &adj[u as usize] {
+                let cv = comp_of[v as usize] as usize;
+                if cv != c {
+                    level[c] = usize::max(level[c], level[cv] + 1);
+                }
+            }
+        }
+        if levels.len() <= level[c] {
+            levels.resize(level[c] + 1, Vec::new());
+        }
+        levels[level[c]].push(c);
+    }
+
+    let mut reach: Vec<Vec<u32>> = vec![Vec::new(); comps.len()];
+    for cs in levels {
+        let sets: Vec<Vec<u32>> = cs
+            .par_iter()
+            .map(|&c| {
+                let mut set = Vec::new();
+                for &u in &comps[c] {
+                    for &v in &adj[u as usize] {
+                        set.push(crate_of[v as usize]);
+                        let cv = comp_of[v as usize] as usize;
+                        if cv != c {
+                            set.extend_from_slice(&reach[cv]);
+                        }
+                    }
+                }
+                set.sort_unstable();
+                set.dedup();
+                set
+            })
+            .collect();
+        for (c, set) in cs.into_iter().zip(sets) {
+            reach[c] = set;
+        }
+    }
+
+    (comp_of, reach)
+}
+
+/// Strongly connected components by Tarjan's algorithm
+///
+/// Components are returned in reverse topological order.
+fn scc(adj: &[Vec<u32>]) -> (Vec<u32>, Vec<Vec<u32>>) {
+    let n = adj.len();
+    let mut order = vec![u32::MAX; n];
+    let mut low = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut stack = Vec::new();
+    let mut comp_of = vec![u32::MAX; n];
+    let mut comps = Vec::new();
+    let mut counter = 0;
+
+    for s in 0..n {
+        if order[s] != u32::MAX {
+            continue;
+        }
+
+        let mut call = vec![(s, 0)];
+        order[s] = counter;
+        low[s] = counter;
+        counter += 1;
+        stack.push(s);
+        on_stack[s] = true;
+
+        while let Some(&(v, i)) = call.last() {
+            if i < adj[v].len() {
+                call.last_mut().unwrap().1 += 1;
+                let w = adj[v][i] as usize;
+                if order[w] == u32::MAX {
+                    order[w] = counter;
+                    low[w] = counter;
+                    counter += 1;
+                    stack.push(w);
+                    on_stack[w] = true;
+                    call.push((w, 0));
+                } else if on_stack[w] {
+                    low[v] = u32::min(low[v], order[w]);
+                }
+            } else {
+                call.pop();
+                if let Some(&(u, _)) = call.last() {
+                    low[u] = u32::min(low[u], low[v]);
+                }
+                if low[v] == order[v] {
+                    let c = comps.len() as u32;
+                    let mut members = Vec::new();
+                    while let Some(w) = stack.pop() {
+                        on_stack[w] = false;
+                        comp_of[w] = c;
+                        members.push(w as u32);
+                        if w == v {
+                            break;
+                        }
+                    }
+                    comps.push(members);
+                }
+            }
+        }
+    }
+
+    (comp_of, comps)
+}
+
+/// Dependencies enabled by the features, and features enabled on each of them
+fn gather_dependencies(
+    krate: &crates_index::Version,
+    enabled_features: &[String],
+) -> Vec<(Dependency, Vec<String>)> {
+    let (enabled_deps, dep_features) = gather_enabled_dependencies(krate, enabled_features);
+
+    let mut ret = Vec::new();
+    for dep in krate.dependencies() {
+        if dep.is_optional() && !enabled_deps.contains(dep.name()) {
+            continue;
+        }
+
+        let mut features = dep.features().to_vec();
+        for &(name, feature) in &dep_features {
+            if name == dep.name() {
+                features.push(String::from(feature));
+            }
+        }
+        ret.push((dep.clone(), features));
+    }
+
+    ret
+}
+
+/// Optional dependencies enabled by the features, and features enabled on dependencies
+///
+/// This follows the feature syntax of cargo:
+///
+/// * `foo` enables the feature, or the optional dependency through its implicit feature
+/// * `dep:foo` enables the optional dependency without the implicit feature
+/// * `foo/bar` enables the dependency and its feature
+/// * `foo?/bar` enables the feature of the dependency only if it is enabled by the others
+fn gather_enabled_dependencies<'a>(
+    krate: &'a crates_index::Version,
+    enabled_features: &'a [String],
+) -> (HashSet<&'a str>, Vec<(&'a str, &'a str)>) {
+    let features = krate.features();
+
+    // optional dependency has an implicit feature unless it is referred by `dep:`
+    let explicit: HashSet<&str> = features
+        .values()
+        .flatten()
+        .filter_map(|x| x.strip_prefix("dep:"))
+        .collect();
+    let implicit: HashSet<&str> = krate
+        .dependencies()
+        .iter()
+        .filter(|x| x.is_optional() && !explicit.contains(x.name()))
+        .map(|x| x.name())
+        .collect();
+
+    let mut enabled_deps = HashSet::new();
+    let mut dep_features = Vec::new();
+    let mut weak_features = Vec::new();
+
+    // checked features break feature loop
+    let mut checked = HashSet::new();
+    let mut stack: Vec<&str> = enabled_features.iter().map(|x| x.as_str()).collect();
+    while let Some(enabled) = stack.pop() {
+        if !checked.insert(enabled) {
+            continue;
+        }
+
+        if let Some(name) = enabled.strip_prefix("dep:") {
+            enabled_deps.insert(name);
+        } else if let Some((name, feature)) = enabled.split_once('/') {
+            if let Some(name) = name.strip_suffix('?') {
+                weak_features.push((name, feature));
+            } else {
+                enabled_deps.insert(name);
+                dep_features.push((name, feature));
+                stack.push(name);
+            }
+        } else if let Some(expanded) = features.get(enabled) {
+            stack.extend(expanded.iter().map(|x| x.as_str()));
+        } else if implicit.contains(enabled) {
+            enabled_deps.insert(enabled);
+        }
+    }
+
+    // weak features are resolved after all dependencies are enabled
+    for (name, feature) in weak_features {
+        let required = krate
+            .dependencies()
+            .iter()
+            .any(|x| x.name() == name && !x.is_optional());
+        if required || enabled_deps.contains(name) {
+            dep_features.push((name, feature));
+        }
+    }
+
+    (enabled_deps, dep_features)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// Normal dependency of index, which is modified by `with`
+    fn dep(name: &str, req: &str, with: Value) -> Value {
+        let mut dep = json!({
+            "name": name, "req": req, "features": [], "optional": false,
+            "default_features": true, "target": null, "kind": "normal"
+        });
+        for (k, v) in with.as_object().unwrap() {
+            dep[k] = v.clone();
+        }
+        dep
+    }
+
+    fn version(name: &str, vers: &str, deps: Vec<Value>, features: Value, yanked: bool) -> Value {
+        json!({
+            "name": name, "vers": vers, "deps": deps, "features": features,
+            "cksum": "0".repeat(64), "yanked": yanked
+        })
+    }
+
+    fn krate(versions: &[Value]) -> Crate {
+        let lines: Vec<String> = versions.iter().map(|x| x.to_string()).collect();
+        Crate::from_slice(lines.join("\n").as_bytes()).unwrap()
+    }
+
+    /// Call `f` with the graph of crates, whose ids are the indexes of `crates`
+    fn with_graph<R>(crates: &[Crate], f: impl FnOnce(Graph) -> R) -> R {
+        let crates: Vec<&Crate> = crates.iter().collect();
+        let id_of: HashMap<&str, u32> = crates
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (c.name(), i as u32))
+            .collect();
+        f(Graph::new(&crates, &id_of))
+    }
+
+    /// Names of crates enabled by the features
+    fn enabled(version: &Value, features: &[&str]) -> Vec<String> {
+        let krate = krate(std::slice::from_ref(version));
+        let features: Vec<String> = features.iter().map(|x| String::from(*x)).collect();
+        let mut ret: Vec<String> = gather_dependencies(&krate.versions()[0], &features)
+            .into_iter()
+            .map(|(dep, _)| String::from(dep.crate_name()))
+            .collect();
+        ret.sort();
+        ret
+    }
+
+    /// Optional dependencies and features of dependencies enabled by the features
+    fn enabled_features(features: Value, enabled: &[&str]) -> (Vec<String>, Vec<String>) {
+        let deps = vec![
+            dep("foo", "1", json!({"optional": true})),
+            dep("bar", "1", json!({"optional": true})),
+        ];
+        let krate = krate(&[version("a", "1.0.0", deps, features, false)]);
+        let enabled: Vec<String> = enabled.iter().map(|x| String::from(*x)).collect();
+        let (deps, dep_features) = gather_enabled_dependencies(&krate.versions()[0], &enabled);
+
+        let mut deps: Vec<String> = deps.into_iter().map(String::from).collect();
+        deps.sort();
+        let mut dep_features: Vec<String> = dep_features
+            .into_iter()
+            .map(|(name, feature)| format!("{}/{}", name, feature))
+            .collect();
+        dep_features.sort();
+        (deps, dep_features)
+    }
+
+    #[test]
+    fn feature_of_implicit_dependency() {
+        let (deps, features) = enabled_features(json!({"x": ["foo"]}), &["x"]);
+        assert_eq!(deps, ["foo"]);
+        assert!(features.is_empty());
+
+        let (deps, _) = enabled_features(json!({}), &["foo"]);
+        assert_eq!(deps, ["foo"]);
+    }
+
+    #[test]
+    fn feature_of_dep_prefix() {
+        let (deps, _) = enabled_features(json!({"x": ["dep:foo"]}), &["x"]);
+        assert_eq!(deps, ["foo"]);
+
+        // implicit feature is removed by `dep:`
+        let (deps, _) = enabled_features(json!({"x": ["dep:foo"]}), &["foo"]);
+        assert!(deps.is_empty());
+    }
+
+    #[test]
+    fn feature_of_dependency_feature() {
+        let (deps, features) = enabled_features(json!({"x": ["foo/std"]}), &["x"]);
+        assert_eq!(deps, ["foo"]);
+        assert_eq!(features, ["foo/std"]);
+    }
+
+    #[test]
+    fn feature_of_weak_dependency_feature() {
+        // the dependency is not enabled
+        let (deps, features) = enabled_features(json!({"x": ["foo?/std"]}), &["x"]);
+        assert!(deps.is_empty());
+        assert!(features.is_empty());
+
+        // the dependency is enabled by the other feature
+        let (deps, features) =
+            enabled_features(json!({"x": ["foo?/std"], "y": ["dep:foo"]}), &["x", "y"]);
+        assert_eq!(deps, ["foo"]);
+        assert_eq!(features, ["foo/std"]);
+    }
+
+    #[test]
+    fn feature_loop() {
+        let features = json!({"x": ["y", "dep:foo"], "y": ["x", "bar"]});
+        let (deps, _) = enabled_features(features, &["x"]);
+        assert_eq!(deps, ["bar", "foo"]);
+    }
+
+    #[test]
+    fn renamed_optional_dependency() {
+        let alias = dep("alias", "1", json!({"package": "real", "optional": true}));
+
+        // implicit feature has the manifest name
+        let v = version(
+            "a",
+            "1.0.0",
+            vec![alias.clone()],
+            json!({"default": ["alias"]}),
+            false,
+        );
+        assert_eq!(enabled(&v, &["default"]), ["real"]);
+        assert!(enabled(&v, &["real"]).is_empty());
+
+        let v = version(
+            "a",
+            "1.0.0",
+            vec![alias.clone()],
+            json!({"x": ["dep:alias"]}),
+            false,
+        );
+        assert_eq!(enabled(&v, &["x"]), ["real"]);
+        assert!(enabled(&v, &["alias"]).is_empty());
+
+        let v = version(
+            "a",
+            "1.0.0",
+            vec![alias],
+            json!({"x": ["alias/std"]}),
+            false,
+        );
+        let krate = krate(&[v]);
+        let deps = gather_dependencies(&krate.versions()[0], &[String::from("x")]);
+        assert_eq!(deps.len(), 1);
+        assert_eq!(deps[0].1, ["std"]);
+    }
+
+    #[test]
+    fn renamed_transitive_dependency() {
+        // a -(alias)-> real -> c
+        let crates = [
+            krate(&[version(
+                "a",
+                "1.0.0",
+                vec![dep(
+                    "alias",
+                    "1",
+                    json!({"package": "real", "optional": true}),
+                )],
+                json!({"default": ["alias"]}),
+                false,
+            )]),
+            krate(&[version(
+                "real",
+                "1.0.0",
+                vec![dep("c", "1", json!({}))],
+                json!({}),
+                false,
+            )]),
+            krate(&[version("c", "1.0.0", vec![], json!({}), false)]),
+        ];
+
+        let direct = with_graph(&crates, |g| {
+            g.direct(0)
+                .iter()
+                .map(|x| String::from(x.crate_name()))
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(direct, ["real"]);
+        let transitive = with_graph(&crates, |g| g.transitive_dependents());
+        assert_eq!(transitive, [0, 1, 2]);
+    }
+
+    #[test]
+    fn resolve_out_of_order_publication() {
+        // 0.9.1 is published after 1.0.0, and 2.0.0-alpha.1 is the last
+        let crates = [krate(&[
+            version("foo", "0.9.0", vec![], json!({}), false),
+            version("foo", "1.0.0", vec![], json!({}), false),
+            version("foo", "0.9.1", vec![], json!({}), false),
+            version("foo", "2.0.0-alpha.1", vec![], json!({}), false),
+        ])];
+
+        let resolved = |req: &str| {
+            with_graph(&crates, |g| {
+                g.resolve(0, &VersionReq::parse(req).unwrap())
+                    .map(|v| g.versions[0][v].as_ref().unwrap().to_string())
+            })
+        };
+        assert_eq!(resolved("^0.9").as_deref(), Some("0.9.1"));
+        assert_eq!(resolved("^1").as_deref(), Some("1.0.0"));
+        // pre-release is skipped unless the requirement has pre-release
+        assert_eq!(resolved("*").as_deref(), Some("1.0.0"));
+        assert_eq!(resolved(">=1.0.0").as_deref(), Some("1.0.0"));
+        assert_eq!(resolved("^2.0.0-alpha").as_deref(), Some("2.0.0-alpha.1"));
+        assert_eq!(resolved("^3").as_deref(), None);
+    }
+
+    #[test]
+    fn resolve_yanked_versions() {
+        // a -> foo -> bar, and only yanked 1.1.0 of foo matches the requirement of a
+        let crates = [
+            krate(&[version(
+                "a",
+                "1.0.0",
+                vec![dep("foo", "^1.1", json!({}))],
+                json!({}),
+                false,
+            )]),
+            krate(&[
+                version("foo", "1.0.0", vec![], json!({}), false),
+                version(
+                    "foo",
+                    "1.1.0",
+                    vec![dep("bar", "1", json!({}))],
+                    json!({}),
+                    true,
+                ),
+            ]),
+            krate(&[version("bar", "1.0.0", vec![], json!({}), false)]),
+        ];
+
+        let resolved = |include_yanked, req: &str| {
+            with_graph(&crates, |g| {
+                let g = g.include_yanked(include_yanked);
+                g.resolve(1, &VersionReq::parse(req).unwrap())
+                    .map(|v| g.versions[1][v].as_ref().unwrap().to_string())
+            })
+        };
+        assert_eq!(resolved(false, "^1").as_deref(), Some("1.0.0"));
+        assert_eq!(resolved(false, "^1.1").as_deref(), None);
+        assert_eq!(resolved(true, "^1").as_deref(), Some("1.1.0"));
+
+        // dependencies of yanked version are traced only if it is included
+        let transitive = with_graph(&crates, |g| g.transitive_dependents());
+        assert_eq!(transitive, [0, 1, 0]);
+        let transitive = with_graph(&crates, |g| g.include_yanked(true).transitive_dependents());
+        assert_eq!(transitive, [0, 1, 2]);
+    }
+
+    #[test]
+    fn dev_dependencies_of_dependencies() {
+        // c -> b -(dev)-> d
+        let crates = [
+            krate(&[version(
+                "b",
+                "1.0.0",
+                vec![dep("d", "1", json!({"kind": "dev"}))],
+                json!({}),
+                false,
+            )]),
+            krate(&[version(
+                "c",
+                "1.0.0",
+                vec![dep("b", "1", json!({}))],
+                json!({}),
+                false,
+            )]),
+            krate(&[version("d", "1.0.0", vec![], json!({}), false)]),
+        ];
+
+        let direct = with_graph(&crates, |g| g.direct(0).len());
+        assert_eq!(direct, 1);
+        let transitive = with_graph(&crates, |g| g.transitive_dependents());
+        assert_eq!(transitive, [1, 0, 1]);
+        let transitive = with_graph(&crates, |g| g.dev_transitive(true).transitive_dependents());
+        assert_eq!(transitive, [1, 0, 2]);
+    }
+
+    #[test]
+    fn target_matches_cfg() {
+        let linux = Target::new("x86_64-unknown-linux-gnu").unwrap();
+        let windows = Target::new("x86_64-pc-windows-msvc").unwrap();
+        assert!(linux.matches("cfg(unix)"));
+        assert!(!windows.matches("cfg(unix)"));
+        assert!(linux.matches("cfg(not(windows))"));
+        assert!(!windows.matches("cfg(not(windows))"));
+        assert!(linux.matches(r#"cfg(all(target_os = "linux", target_arch = "x86_64"))"#));
+
+        // plain triple matches itself only
+        assert!(linux.matches("x86_64-unknown-linux-gnu"));
+        assert!(!windows.matches("x86_64-unknown-linux-gnu"));
+
+        // `test` and features are not set for dependencies, and invalid expression never matches
+        assert!(!linux.matches("cfg(test)"));
+        assert!(linux.matches("cfg(not(test))"));
+        assert!(!linux.matches(r#"cfg(feature = "std")"#));
+        assert!(!linux.matches("cfg(unix"));
+    }
+
+    #[test]
+    fn target_specific_dependencies() {
+        // a -(windows)-> b -> d, and a -(unix)-> c
+        let crates = [
+            krate(&[version(
+                "a",
+                "1.0.0",
+                vec![
+                    dep("b", "1", json!({"target": "cfg(windows)"})),
+                    dep("c", "1", json!({"target": "cfg(unix)"})),
+                ],
+                json!({}),
+                false,
+            )]),
+            krate(&[version(
+                "b",
+                "1.0.0",
+                vec![dep("d", "1", json!({}))],
+                json!({}),
+                false,
+            )]),
+            krate(&[version("c", "1.0.0", vec![], json!({}), false)]),
+            krate(&[version("d", "1.0.0", vec![], json!({}), false)]),
+        ];
+
+        let counts = |target: Option<&str>| {
+            let target = target.map(|x| Target::new(x).unwrap());
+            with_graph(&crates, |g| {
+                let g = g.target(target.as_ref());
+                let direct: Vec<String> = g
+                    .direct(0)
+                    .iter()
+                    .map(|x| String::from(x.crate_name()))
+                    .collect();
+                (direct, g.transitive_dependents())
+            })
+        };
+        assert_eq!(
+            counts(None),
+            (vec!["b".into(), "c".into()], vec![0, 1, 1, 2])
+        );
+        assert_eq!(
+            counts(Some("x86_64-unknown-linux-gnu")),
+            (vec!["c".into()], vec![0, 0, 1, 1])
+        );
+        assert_eq!(
+            counts(Some("x86_64-pc-windows-msvc")),
+            (vec!["b".into()], vec![0, 1, 0, 2])
+        );
+    }
+
+    /// Crates reachable from the node through one or more edges by DFS
+    fn naive_closure(adj: &[Vec<u32>], crate_of: &[u32], node: usize) -> Vec<u32> {
+        let mut visited = vec![false; adj.len()];
+        let mut stack: Vec<u32> = adj[node].clone();
+        let mut ret = Vec::new();
+        while let Some(v) = stack.pop() {
+            if !visited[v as usize] {
+                visited[v as usize] = true;
+                ret.push(crate_of[v as usize]);
+                stack.extend_from_slice(&adj[v as usize]);
+            }
+        }
+        ret.sort_unstable();
+        ret.dedup();
+        ret
+    }
+
+    fn check_closure(adj: &[Vec<u32>], crate_of: &[u32]) {
+        let (comp_of, reach) = closure(adj, crate_of);
+        for node in 0..adj.len() {
+            assert_eq!(
+                reach[comp_of[node] as usize],
+                naive_closure(adj, crate_of, node),
+                "node {} of {:?}",
+                node,
+                adj
+            );
+        }
+    }
+
+    #[test]
+    fn closure_of_cycles_and_self_loops() {
+        // self-loop, 2-cycle, cycle reached from a chain, and a chain out of a cycle
+        check_closure(&[vec![0]], &[0]);
+        check_closure(&[vec![1], vec![0]], &[0, 1]);
+        check_closure(&[vec![1], vec![2], vec![3], vec![1]], &[0, 1, 2, 3]);
+        check_closure(&[vec![1], vec![0, 2], vec![3], vec![]], &[0, 1, 2, 3]);
+        // nodes of the same crate with different features
+        check_closure(&[vec![1], vec![2], vec![1, 3], vec![]], &[0, 1, 1, 2]);
+    }
+
+    /// Linear congruential generator to make random graphs reproducible
+    fn random(mut seed: u64) -> impl FnMut(u64) -> u64 {
+        move |n| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        }
+    }
+
+    #[test]
+    fn closure_of_random_graphs() {
+        let mut random = random(1);
+        for _ in 0..200 {
+            let n = random(12) as usize + 1;
+            let crates = random(n as u64) + 1;
+            let adj: Vec<Vec<u32>> = (0..n)
+                .map(|_| (0..random(4)).map(|_| random(n as u64) as u32).collect())
+                .collect();
+            let crate_of: Vec<u32> = (0..n).map(|_| random(crates) as u32).collect();
+            check_closure(&adj, &crate_of);
+        }
+    }
+
+    /// Crates reachable from each root through `Graph::edges` by DFS
+    fn naive_transitive_dependents(g: &Graph) -> Vec<u64> {
+        let mut ret = vec![0; g.crates.len()];
+        for id in 0..g.crates.len() as u32 {
+            let mut visited = HashSet::new();
+            let mut reached = HashSet::new();
+            let mut stack = g.edges(&g.root(id));
+            while let Some(node) = stack.pop() {
+                if visited.insert(node.clone()) {
+                    reached.insert(node.id);
+                    stack.extend(g.edges(&node));
+                }
+            }
+            for x in reached {
+                ret[x as usize] += 1;
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn transitive_dependents_of_random_indexes() {
+        let mut random = random(2);
+        let reqs = ["1", "2", "*", "^1.1"];
+        let kinds = ["normal", "dev", "build"];
+        let policies = [
+            FeaturePolicy::Default,
+            FeaturePolicy::All,
+            FeaturePolicy::NoDefault,
+        ];
+
+        let mut total = 0;
+        for _ in 0..200 {
+            let n = random(6) + 1;
+            let crates: Vec<Crate> = (0..n)
+                .map(|i| {
+                    let name = format!("c{}", i);
+                    let versions: Vec<Value> = ["1.0.0", "1.1.0", "2.0.0"]
+                        .iter()
+                        .take(random(3) as usize + 1)
+                        .map(|vers| {
+                            // optional dependencies are enabled by `x` or their implicit features
+                            let mut deps = Vec::new();
+                            let mut x = Vec::new();
+                            for j in 0..random(4) {
+                                let target = format!("c{}", random(n));
+                                let optional = random(2) == 0;
+                                let with = json!({
+                                    "package": target,
+                                    "kind": kinds[random(3) as usize],
+                                    "optional": optional,
+                                    "default_features": random(2) == 0,
+                                });
+                                let alias = format!("d{}", j);
+                                match random(3) {
+                                    0 if optional => x.push(format!("dep:{}", alias)),
+                                    1 => x.push(format!("{}/x", alias)),
+                                    _ => x.push(format!("{}?/x", alias)),
+                                }
+                                deps.push(dep(&alias, reqs[random(4) as usize], with));
+                            }
+                            let default: Vec<&str> =
+                                if random(2) == 0 { vec!["x"] } else { vec![] };
+                            version(
+                                &name,
+                                vers,
+                                deps,
+                                json!({"x": x, "default": default}),
+                                false,
+                            )
+                        })
+                        .collect();
+                    krate(&versions)
+                })
+                .collect();
+
+            let dev_transitive = random(2) == 0;
+            let features = policies[random(3) as usize];
+            let (actual, expected) = with_graph(&crates, |g| {
+                let g = g.dev_transitive(dev_transitive).features(features);
+                (g.transitive_dependents(), naive_transitive_dependents(&g))
+            });
+            assert_eq!(actual, expected);
+            total += actual.iter().sum::<u64>();
+        }
+        // most indexes should have dependents not to pass trivially
+        assert!(total > 200, "{}", total);
+    }
+}
@@ -1,14 +1,17 @@
 mod db;
+mod graph;
 mod plotter;
 
-use crate::db::Db;
+use crate::db::{Db, DbError, Metric, UpdateOption, DB_URL};
+use crate::graph::FeaturePolicy;
 use crate::plotter::Plotter;
 use anyhow::{anyhow, Context, Error};
 use cargo_metadata::MetadataCommand;
-use chrono::{Duration, Utc};
+use chrono::{Duration, NaiveDate, Utc};
 use directories::ProjectDirs;
+use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::{clap, StructOpt};
 
 // ---------------------------------------------------------------------------------------------------------------------
@@ -22,6 +25,7 @@ pub enum CargoOpt {
     #[structopt(long_version(option_env!("LONG_VERSION").unwrap_or(env!("CARGO_PKG_VERSION"))))]
     #[structopt(setting(clap::AppSettings::ColoredHelp))]
     #[structopt(setting(clap::AppSettings::DeriveDisplayOrder))]
+    #[structopt(setting(clap::AppSettings::DisableHelpSubcommand))]
     Trend(Opt),
 }
 
@@ -59,6 +63,61 @@ pub struct Opt {
     #[structopt(value_name = "BRANCH", short = "b", long = "branch")]
     branch: Option<String>,
 
+    /// Local clone of crates.io-index kept between updates
+    #[structopt(
+        value_name = "PATH",
+        long = "index-path",
+        requires = "update",
+        conflicts_with = "sparse"
+    )]
+    index_path: Option<PathBuf>,
+
+    /// URL of registry index
+    #[structopt(value_name = "URL", long = "index-url", requires = "update")]
+    index_url: Option<String>,
+
+    /// Registry name defined in .cargo/config.toml
+    #[structopt(
+        value_name = "NAME",
+        long = "registry",
+        requires = "update",
+        conflicts_with = "index_url"
+    )]
+    registry: Option<String>,
+
+    /// Save db every N revisions while updating ( 10 by default, and 0 disables it )
+    #[structopt(value_name = "N", long = "checkpoint", requires = "update")]
+    checkpoint: Option<usize>,
+
+    /// Number of threads to update db ( the number of CPUs by default )
+    #[structopt(value_name = "N", short = "j", long = "jobs", requires = "update")]
+    jobs: Option<usize>,
+
+    /// Count dev-dependencies of dependencies as transitive dependents ( compatible with older versions )
+    #[structopt(long = "dev-transitive", requires = "update")]
+    dev_transitive: bool,
+
+    /// Resolve dependencies to yanked versions too
+    #[structopt(long = "include-yanked", requires = "update")]
+    include_yanked: bool,
+
+    /// Features enabled on dependent crates, which should be the same as db to plot
+    #[structopt(
+        value_name = "POLICY",
+        long = "features",
+        default_value = "default",
+        possible_values = &["default", "all", "no-default"]
+    )]
+    features: FeaturePolicy,
+
+    /// Update db from sparse index instead of git index ( URL or directory )
+    #[structopt(value_name = "INDEX", long = "sparse", requires = "update")]
+    sparse: Option<String>,
+
+    /// File listing crate names to fetch from sparse HTTP index
+    #[structopt(value_name = "PATH", long = "crate-list", requires = "update")]
+    crate_list: Option<PathBuf>,
+
     /// Plot fraction of crates.io
     #[structopt(long = "relative")]
     relative: bool,
@@ -67,6 +126,45 @@ pub struct Opt {
     #[structopt(long = "transitive")]
     transitive: bool,
 
+    /// Plot direct dependents of the kind only
+    #[structopt(
+        value_name = "KIND",
+        long = "kind",
+        possible_values = &["normal", "dev", "build"],
+        conflicts_with_all = &["transitive", "top_transitive"]
+    )]
+    kind: Option<String>,
+
+    /// Plot share of yanked versions
+    #[structopt(
+        long = "yanked-share",
+        conflicts_with_all = &["relative", "transitive", "kind", "target", "top_transitive"]
+    )]
+    yanked_share: bool,
+
+    /// Plot direct dependents of each major version
+    #[structopt(
+        long = "major",
+        conflicts_with_all = &["transitive", "kind", "target", "yanked_share", "top_transitive"]
+    )]
+    major: bool,
+
+    /// Plot direct dependents whose requirement matches the newest release next to the others
+    #[structopt(
+        long = "latest",
+        conflicts_with_all = &["transitive", "kind", "target", "yanked_share", "major", "top_transitive"]
+    )]
+    latest: bool,
+
+    /// Target triple to count dependents on ( some triples can be given to update db )
+    #[structopt(
+        value_name = "TRIPLE",
+        long = "target",
+        number_of_values = 1,
+        conflicts_with = "kind"
+    )]
+    target: Vec<String>,
+
     /// The most trending crates
     #[structopt(
             value_name = "N",
@@ -83,15 +181,159 @@ pub struct Opt {
     #[structopt(value_name = "N", long = "top-transitive", conflicts_with_all = &["top_trend", "top_dependent"])]
     top_transitive: Option<usize>,
 
+    /// Location of db to fetch ( URL, file:// URL or directory )
+    #[structopt(value_name = "URL", long = "db-url", env = "CARGO_TREND_DB_URL")]
+    db_url: Option<String>,
+
+    /// Use cached db without fetching
+    #[structopt(long = "offline")]
+    offline: bool,
+
+    /// Repair corrupted db by fetching it from `--db-url` again
+    #[structopt(long = "repair")]
+    repair: bool,
+
     /// Duration by week
     #[structopt(long = "duration")]
     duration: Option<i64>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// List direct dependents of a crate
+    Dependents {
+        /// Crate
+        #[structopt(value_name = "CRATE")]
+        name: String,
+
+        /// Date to list dependents ( the last update by default )
+        #[structopt(value_name = "DATE", long = "date")]
+        date: Option<NaiveDate>,
+
+        /// List dependents added or removed since the date
+        #[structopt(value_name = "DATE", long = "since")]
+        since: Option<NaiveDate>,
+    },
+
+    /// Show dependents gained or lost between two dates
+    Diff {
+        /// Crate
+        #[structopt(value_name = "CRATE")]
+        name: String,
+
+        /// Start date
+        #[structopt(value_name = "FROM")]
+        from: NaiveDate,
+
+        /// End date ( the last update by default )
+        #[structopt(value_name = "TO")]
+        to: Option<NaiveDate>,
+    },
+
+    /// Show dependents switched between two crates at each snapshot
+    Migration {
+        /// Crate migrated from
+        #[structopt(value_name = "FROM")]
+        from: String,
+
+        /// Crate migrated to
+        #[structopt(value_name = "TO")]
+        to: String,
+    },
+
+    /// Manage db
+    Db(DbCommand),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum DbCommand {
+    /// Upgrade db of older versions to the current version
+    Migrate {
+        /// Directory of db
+        #[structopt(value_name = "PATH")]
+        dir: PathBuf,
+    },
 }
 
 // ---------------------------------------------------------------------------------------------------------------------
 // Functions
 // ---------------------------------------------------------------------------------------------------------------------
 
+fn load_db(dir: &Path, crates: Option<&[String]>) -> Result<Db, Error> {
+    Db::load(dir, crates).map_err(|e| {
+        if let Some(DbError::Corrupted { .. } | DbError::NotFound(_)) = e.downcast_ref::<DbError>()
+        {
+            e.context("failed to load db, `--repair` option may fix it")
+        } else {
+            e
+        }
+    })
+}
+
+/// Dependencies of the workspace members
+fn manifest_dependencies(manifest_path: Option<PathBuf>) -> Result<Vec<String>, Error> {
+    let mut cmd = MetadataCommand::new();
+    if let Some(path) = manifest_path {
+        cmd.manifest_path(path);
+    }
+    let metadata = cmd.exec()?;
+
+    let mut ret = Vec::new();
+    for package in metadata.packages {
+        if metadata
+            .workspace_members
+            .iter()
+            .any(|x| x.repr == package.id.repr)
+        {
+            for dep in package.dependencies {
+                ret.push(dep.name);
+            }
+        }
+    }
+    Ok(ret)
+}
+
+fn registry_index(name: &str) -> Result<String, Error> {
+    let key = format!(
+        "CARGO_REGISTRIES_{}_INDEX",
+        name.to_uppercase().replace('-', "_")
+    );
+    if let Ok(url) = env::var(key) {
+        return Ok(url);
+    }
+
+    // the nearest config has priority like cargo
+    let mut paths = Vec::new();
+    for dir in env::current_dir()?.ancestors() {
+        paths.push(dir.join(".cargo").join("config.toml"));
+        paths.push(dir.join(".cargo").join("config"));
+    }
+    if let Ok(dir) = home::cargo_home() {
+        paths.push(dir.join("config.toml"));
+        paths.push(dir.join("config"));
+    }
+
+    for path in paths {
+        if let Ok(text) = fs::read_to_string(&path) {
+            let config: toml::Table = toml::from_str(&text)
+                .with_context(|| format!("failed to parse {}", path.to_string_lossy()))?;
+            let index = config
+                .get("registries")
+                .and_then(|x| x.get(name))
+                .and_then(|x| x.get("index"))
+                .and_then(|x| x.as_str());
+            if let Some(index) = index {
+                return Ok(String::from(index));
+            }
+        }
+    }
+
+    Err(anyhow!("registry {} is not found in cargo config", name))
+}
+
 // ---------------------------------------------------------------------------------------------------------------------
 // Main
 // ---------------------------------------------------------------------------------------------------------------------
@@ -111,15 +353,58 @@ fn main() {
 fn run() -> Result<(), Error> {
     let CargoOpt::Trend(opt) = CargoOpt::from_args();
 
-    let mut transitive = opt.transitive;
+    if let Some(Command::Db(DbCommand::Migrate { dir })) = &opt.command {
+        return Db::migrate(dir);
+    }
+
+    let mut metric = match opt.kind.as_deref() {
+        Some("normal") => Metric::Normal,
+        Some("dev") => Metric::Dev,
+        Some("build") => Metric::Build,
+        _ if opt.yanked_share => Metric::YankedShare,
+        _ if opt.transitive => Metric::Transitive,
+        _ => Metric::Direct,
+    };
 
     if let Some(path) = opt.update {
-        let mut db = if path.exists() {
-            Db::load(&path)?
+        let mut db = if path.join("db.json").exists() {
+            if opt.repair {
+                // db built by `--update` may be of other registries, so the default db isn't used
+                let source = opt
+                    .db_url
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("`--db-url` is required to repair db of `--update`"))?;
+                Db::repair(&path, source, None)?;
+            }
+            load_db(&path, None)?
         } else {
             Db::new()
         };
-        db.update(opt.branch)?;
+        let index_url = if let Some(registry) = opt.registry {
+            Some(registry_index(&registry)?)
+        } else {
+            opt.index_url
+        };
+
+        // registry index with sparse+ prefix can't be cloned
+        let (index_url, sparse) = match index_url {
+            Some(url) if url.starts_with("sparse+") => (None, Some(url)),
+            url => (url, opt.sparse),
+        };
+
+        let option = UpdateOption::new()
+            .branch(opt.branch)
+            .sparse(sparse)
+            .crate_list(opt.crate_list)
+            .index_path(opt.index_path)
+            .index_url(index_url)
+            .checkpoint(path.clone(), opt.checkpoint.unwrap_or(10))
+            .jobs(opt.jobs)
+            .dev_transitive(opt.dev_transitive)
+            .include_yanked(opt.include_yanked)
+            .features(opt.features)
+            .targets(opt.target);
+        db.update(&option)?;
         db.save(&path)?;
 
         return Ok(());
@@ -135,14 +420,187 @@ fn run() -> Result<(), Error> {
         )
     })?;
 
-    Db::fetch(data_dir)?;
-    let db = Db::load(&data_dir)?;
+    // crates to plot or inspect, which are fetched and loaded without the others
+    // ( all crates are required if None )
+    let crates = match &opt.command {
+        Some(Command::Dependents { name, .. }) => Some(vec![name.clone()]),
+        Some(Command::Migration { from, to }) => Some(vec![from.clone(), to.clone()]),
+        Some(Command::Diff { .. }) => None,
+        _ if opt.top_trend.is_some()
+            || opt.top_dependent.is_some()
+            || opt.top_transitive.is_some() =>
+        {
+            None
+        }
+        _ if opt.crates.is_empty() => Some(manifest_dependencies(opt.manifest_path)?),
+        _ => Some(opt.crates),
+    };
 
-    let start_date = if let Some(duration) = opt.duration {
-        Some((Utc::now() - Duration::weeks(duration)).date_naive())
+    let cached = if opt.offline {
+        true
+    } else if let Err(e) = Db::fetch(
+        data_dir,
+        opt.db_url.as_deref().unwrap_or(DB_URL),
+        crates.as_deref(),
+    ) {
+        eprintln!("Warning: failed to fetch db, so cached db is used");
+        eprintln!("  Caused by: {}", e);
+        true
     } else {
-        None
+        false
+    };
+
+    if opt.repair {
+        Db::repair(
+            data_dir,
+            opt.db_url.as_deref().unwrap_or(DB_URL),
+            crates.as_deref(),
+        )?;
+    }
+
+    let db = load_db(data_dir, crates.as_deref())?;
+
+    // dependents counted with different features can't be compared
+    if db.features != opt.features {
+        return Err(anyhow!(
+            "db is built with feature policy {}, but {} is given",
+            db.features,
+            opt.features
+        ));
+    }
+
+    if cached {
+        let age = Utc::now() - db.update;
+        eprintln!(
+            "Cached db was updated at {} ( {} days ago )",
+            db.update.date_naive(),
+            age.num_days()
+        );
+    }
+
+    // dependents on the target are counted separately from the others
+    let target = match opt.target.as_slice() {
+        [] => None,
+        [triple] => Some(db.targets.iter().position(|x| x == triple).ok_or_else(|| {
+            anyhow!(
+                "target {} is not found in db ( available targets: [{}] )",
+                triple,
+                db.targets.join(", ")
+            )
+        })?),
+        _ => return Err(anyhow!("only one target can be plotted")),
     };
+    metric = match (metric, target) {
+        (Metric::Direct, Some(i)) => Metric::TargetDirect(i),
+        (Metric::Transitive, Some(i)) => Metric::TargetTransitive(i),
+        (metric, _) => metric,
+    };
+
+    // the default db keeps the counting of the older versions to be continuous with them
+    let transitive = matches!(metric, Metric::Transitive | Metric::TargetTransitive(_));
+    if db.dev_transitive && (transitive || opt.top_transitive.is_some()) {
+        eprintln!(
+            "Warning: db counts dev-dependencies of dependencies as transitive dependents ( built with `--dev-transitive` )"
+        );
+    }
+    if db.include_yanked {
+        eprintln!(
+            "Warning: db resolves dependencies to yanked versions too ( built with `--include-yanked` )"
+        );
+    }
+
+    match opt.command {
+        Some(Command::Dependents { name, date, since }) => {
+            let date = date.unwrap_or_else(|| db.update.date_naive());
+            db.check_edges(date)?;
+            let dependents = db.dependents(&name, date);
+            if let Some(since) = since {
+                db.check_edges(since)?;
+                let old = db.dependents(&name, since);
+                for x in dependents.difference(&old) {
+                    println!("+ {}", x);
+                }
+                for x in old.difference(&dependents) {
+                    println!("- {}", x);
+                }
+            } else {
+                for x in dependents {
+                    println!("{}", x);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Diff { name, from, to }) => {
+            let to = to.unwrap_or_else(|| db.update.date_naive());
+            let (gained, lost) = db.diff(&name, from, to)?;
+            println!("Gained ( {} crates ):", gained.len());
+            for x in gained {
+                println!("  {}", x);
+            }
+            println!("Lost ( {} crates ):", lost.len());
+            for (x, switched) in lost {
+                match switched {
+                    Some(switched) => println!("  {} -> {}", x, switched),
+                    None => println!("  {}", x),
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Migration { from, to }) => {
+            let header = [
+                String::from("Date"),
+                format!("{} -> {}", from, to),
+                format!("{} -> {}", to, from),
+                from.clone(),
+                to.clone(),
+            ];
+            let width: Vec<usize> = header.iter().map(|x| usize::max(x.len(), 10)).collect();
+            let row = |x: [String; 5]| {
+                let mut line = format!("{:<w$}", x[0], w = width[0]);
+                for (x, w) in x.iter().zip(&width).skip(1) {
+                    line.push_str(&format!("  {:>w$}", x, w = w));
+                }
+                println!("{}", line.trim_end());
+            };
+
+            let migration = db.migration(&from, &to);
+            if let Some(x) = migration.first() {
+                if db.edges_since.is_none_or(|since| x.time < since) {
+                    eprintln!(
+                        "Warning: db has no dependents before {}, so switches before it are not counted",
+                        db.edges_since.unwrap_or(db.update).date_naive()
+                    );
+                }
+            }
+
+            row(header);
+            let mut total = (0, 0);
+            for x in migration {
+                total.0 += x.forward;
+                total.1 += x.backward;
+                row([
+                    x.time.date_naive().to_string(),
+                    x.forward.to_string(),
+                    x.backward.to_string(),
+                    x.dependents.0.to_string(),
+                    x.dependents.1.to_string(),
+                ]);
+            }
+            row([
+                String::from("Total"),
+                total.0.to_string(),
+                total.1.to_string(),
+                String::new(),
+                String::new(),
+            ]);
+            return Ok(());
+        }
+        Some(Command::Db(_)) | None => (),
+    }
+
+    let start_date = opt
+        .duration
+        .map(|duration| (Utc::now() - Duration::weeks(duration)).date_naive());
 
     let targets = if let Some(top_trend) = opt.top_trend {
         let mut trend = Vec::new();
@@ -159,17 +617,10 @@ fn run() -> Result<(), Error> {
 
             if let Some(entry_oldest) = entry_oldest {
                 if let Some(entry_newest) = entry_newest {
-                    let (dep_oldest, dep_newest) = if transitive {
-                        (
-                            entry_oldest.transitive_dependents,
-                            entry_newest.transitive_dependents,
-                        )
-                    } else {
-                        (
-                            entry_oldest.direct_dependents,
-                            entry_newest.direct_dependents,
-                        )
-                    };
+                    let (dep_oldest, dep_newest) = (
+                        entry_oldest.dependents(metric),
+                        entry_newest.dependents(metric),
+                    );
 
                     let (dep_oldest, dep_newest) = if opt.relative {
                         (
@@ -194,12 +645,16 @@ fn run() -> Result<(), Error> {
         }
         ret
     } else if let Some(top_dependent) = opt.top_dependent {
-        transitive = false;
+        metric = match metric {
+            Metric::Transitive => Metric::Direct,
+            Metric::TargetTransitive(i) => Metric::TargetDirect(i),
+            metric => metric,
+        };
 
         let mut trend = Vec::new();
         for (name, entries) in &db.map {
             if let Some(entry) = entries.last() {
-                trend.push((entry.direct_dependents, name));
+                trend.push((entry.dependents(metric), name));
             }
         }
 
@@ -213,12 +668,15 @@ fn run() -> Result<(), Error> {
         }
         ret
     } else if let Some(top_transitive) = opt.top_transitive {
-        transitive = true;
+        metric = match target {
+            Some(i) => Metric::TargetTransitive(i),
+            None => Metric::Transitive,
+        };
 
         let mut trend = Vec::new();
         for (name, entries) in &db.map {
             if let Some(entry) = entries.last() {
-                trend.push((entry.transitive_dependents, name));
+                trend.push((entry.dependents(metric), name));
             }
         }
 
@@ -231,37 +689,20 @@ fn run() -> Result<(), Error> {
             }
         }
         ret
-    } else if opt.crates.is_empty() {
-        let mut cmd = MetadataCommand::new();
-        if let Some(path) = opt.manifest_path {
-            cmd.manifest_path(path);
-        }
-        let metadata = cmd.exec()?;
-
-        let mut ret = Vec::new();
-        for package in metadata.packages {
-            if metadata
-                .workspace_members
-                .iter()
-                .any(|x| x.repr == package.id.repr)
-            {
-                for dep in package.dependencies {
-                    ret.push(dep.name);
-                }
-            }
-        }
-        ret
     } else {
-        opt.crates
+        crates.unwrap_or_default()
     };
 
-    let plotter = Plotter::new().size((opt.x_size, opt.y_size));
+    let plotter = Plotter::new()
+        .size((opt.x_size, opt.y_size))
+        .major(opt.major)
+        .latest(opt.latest);
     plotter.plot(
         opt.output,
         targets.as_slice(),
         &db,
         opt.relative,
-        transitive,
+        metric,
         start_date,
     )?;
 
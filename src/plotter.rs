@@ -1,4 +1,4 @@
-use crate::db::Db;
+use crate::db::{compat_range_key, Db, Entry, Metric};
 use anyhow::Error;
 use chrono::{NaiveDate, TimeZone, Utc};
 use plotters::prelude::*;
@@ -8,11 +8,28 @@ use std::path::Path;
 
 pub struct Plotter {
     size: (u32, u32),
+    major: bool,
+    latest: bool,
+}
+
+/// A line plotted for a crate
+#[derive(Clone, Copy)]
+enum Line<'a> {
+    /// Dependents of the metric
+    Metric,
+    /// Direct dependents whose requirement matches the newest release
+    Latest,
+    /// Direct dependents of the semver compatible range
+    Compat(&'a str),
 }
 
 impl Plotter {
     pub fn new() -> Self {
-        Plotter { size: (1200, 800) }
+        Plotter {
+            size: (1200, 800),
+            major: false,
+            latest: false,
+        }
     }
 
     pub fn size(mut self, size: (u32, u32)) -> Self {
@@ -20,24 +37,36 @@ impl Plotter {
         self
     }
 
+    /// Plot direct dependents of each major version as separated lines
+    pub fn major(mut self, major: bool) -> Self {
+        self.major = major;
+        self
+    }
+
+    /// Plot direct dependents on the newest release next to the metric
+    pub fn latest(mut self, latest: bool) -> Self {
+        self.latest = latest;
+        self
+    }
+
     pub fn plot<T: AsRef<Path>, U: AsRef<str>>(
         &self,
         path: T,
         targets: &[U],
         db: &Db,
         relative: bool,
-        transitive: bool,
+        metric: Metric,
         start_date: Option<NaiveDate>,
     ) -> Result<(), Error> {
         let extension = path.as_ref().extension();
         match extension {
             Some(x) if x == OsStr::new("svg") => {
                 let backend = SVGBackend::new(path.as_ref(), self.size);
-                self.plot_with_backend(backend, targets, db, relative, transitive, start_date)
+                self.plot_with_backend(backend, targets, db, relative, metric, start_date)
             }
             _ => {
                 let backend = BitMapBackend::new(path.as_ref(), self.size);
-                self.plot_with_backend(backend, targets, db, relative, transitive, start_date)
+                self.plot_with_backend(backend, targets, db, relative, metric, start_date)
             }
         }
     }
@@ -48,7 +77,7 @@ impl Plotter {
         targets: &[U],
         db: &Db,
         relative: bool,
-        transitive: bool,
+        metric: Metric,
         start_date: Option<NaiveDate>,
     ) -> Result<(), Error>
     where
@@ -56,18 +85,41 @@ impl Plotter {
         T::ErrorType: 'static,
         U: AsRef<str>,
     {
-        let mut x_min = Utc
-            .timestamp_opt(std::i32::MAX as i64, 0)
-            .unwrap()
-            .date_naive();
+        let mut x_min = Utc.timestamp_opt(i32::MAX as i64, 0).unwrap().date_naive();
         let mut x_max = Utc.timestamp_opt(0, 0).unwrap().date_naive();
-        let mut y_min = std::f32::MAX;
-        let mut y_max = std::f32::MIN;
+        let mut y_min = f32::MAX;
+        let mut y_max = f32::MIN;
 
+        // lines are sorted by crate name and major version
         let mut plots = BTreeMap::new();
         for target in targets {
-            let mut plot = Vec::new();
-            if let Some(entries) = db.map.get(target.as_ref()) {
+            let entries = db
+                .map
+                .get(target.as_ref())
+                .map(|x| x.as_slice())
+                .unwrap_or_default();
+
+            let mut lines = Vec::new();
+            if self.major {
+                let mut ranges: Vec<&str> = Vec::new();
+                for entry in entries {
+                    for (range, _) in &entry.compat_dependents {
+                        if !ranges.contains(&range.as_str()) {
+                            ranges.push(range);
+                        }
+                    }
+                }
+                ranges.sort_by_key(|x| compat_range_key(x));
+                lines.extend(ranges.into_iter().map(Line::Compat));
+            } else {
+                lines.push(Line::Metric);
+                if self.latest {
+                    lines.push(Line::Latest);
+                }
+            }
+
+            for (i, line) in lines.into_iter().enumerate() {
+                let mut plot = Vec::new();
                 for entry in entries {
                     let x_val = entry.time.date_naive();
 
@@ -77,16 +129,7 @@ impl Plotter {
                         }
                     }
 
-                    let dependents = if transitive {
-                        entry.transitive_dependents
-                    } else {
-                        entry.direct_dependents
-                    };
-                    let y_val = if relative {
-                        dependents as f32 / entry.total_crates as f32
-                    } else {
-                        dependents as f32
-                    };
+                    let y_val = self.value(entry, relative, metric, line);
                     plot.push((x_val, y_val));
 
                     x_min = if x_min > x_val { x_val } else { x_min };
@@ -94,8 +137,14 @@ impl Plotter {
                     y_min = f32::min(y_min, y_val);
                     y_max = f32::max(y_max, y_val);
                 }
+
+                let label = match line {
+                    Line::Metric => String::from(target.as_ref()),
+                    Line::Latest => format!("{} latest", target.as_ref()),
+                    Line::Compat(range) => format!("{} {}", target.as_ref(), range),
+                };
+                plots.insert((String::from(target.as_ref()), i), (label, plot));
             }
-            plots.insert(String::from(target.as_ref()), plot);
         }
 
         y_min *= 0.9;
@@ -109,7 +158,9 @@ impl Plotter {
             .y_label_area_size(50)
             .build_cartesian_2d(x_min..x_max, y_min..y_max)?;
 
-        let y_desc = if relative {
+        let y_desc = if metric == Metric::YankedShare {
+            "Share of yanked versions"
+        } else if relative {
             "Fraction of dependent crates"
         } else {
             "Number of dependent crates"
@@ -124,7 +175,7 @@ impl Plotter {
 
         let hue_step = 1.0 / plots.len() as f64;
         let mut hue = 0.0;
-        for (target, plot) in &plots {
+        for (target, plot) in plots.values() {
             let color = HSLColor(hue, 0.8, 0.5);
             hue += hue_step;
 
@@ -134,20 +185,41 @@ impl Plotter {
                 stroke_width: 2,
             };
 
-            let anno = chart.draw_series(LineSeries::new(plot.clone(), style.clone()))?;
+            let anno = chart.draw_series(LineSeries::new(plot.clone(), style))?;
             anno.label(target).legend(move |(x, y)| {
-                plotters::prelude::PathElement::new(vec![(x, y), (x + 20, y)], style.clone())
+                plotters::prelude::PathElement::new(vec![(x, y), (x + 20, y)], style)
             });
         }
 
         chart
             .configure_series_labels()
             .position(SeriesLabelPosition::MiddleLeft)
-            .background_style(&WHITE)
-            .border_style(&BLACK)
+            .background_style(WHITE)
+            .border_style(BLACK)
             .draw()?;
 
         chart.plotting_area().present()?;
         Ok(())
     }
+
+    fn value(&self, entry: &Entry, relative: bool, metric: Metric, line: Line) -> f32 {
+        let dependents = match line {
+            Line::Metric => entry.dependents(metric),
+            Line::Latest => entry.latest_dependents,
+            Line::Compat(range) => entry
+                .compat_dependents
+                .iter()
+                .find(|x| x.0 == range)
+                .map(|x| x.1)
+                .unwrap_or(0),
+        };
+
+        if metric == Metric::YankedShare && matches!(line, Line::Metric) {
+            entry.yanked_share() as f32
+        } else if relative {
+            dependents as f32 / entry.total_crates as f32
+        } else {
+            dependents as f32
+        }
+    }
 }